@@ -0,0 +1,83 @@
+use crate::{
+    error::{Error, Result},
+    settings::ServiceConfig,
+};
+use std::{
+    collections::BTreeSet,
+    io,
+    net::{Ipv4Addr, TcpListener, UdpSocket},
+};
+
+// Attempts at a random port before giving up, when no port_range is set.
+const RANDOM_ATTEMPTS: usize = 16;
+
+// Whether nothing listens on `port` right now, for the service type's protocol.
+fn available(port: u16, udp: bool) -> bool {
+    if udp {
+        UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).is_ok()
+    } else {
+        TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).is_ok()
+    }
+}
+
+fn available_port(udp: bool) -> io::Result<u16> {
+    if udp {
+        UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?.local_addr()
+    } else {
+        TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0))?.local_addr()
+    }
+    .map(|address| address.port())
+}
+
+// port = 0 means "pick a free port". `remembered` is the port the entry got last time
+// and is kept as long as it fits port_range and isn't `taken` by another entry; the
+// service itself may be listening on it already. Otherwise a port is picked in
+// port_range (anywhere when unset) that isn't taken and that nothing listens on.
+pub fn resolve_port(
+    key: &str,
+    service_config: &ServiceConfig,
+    remembered: Option<u16>,
+    taken: &BTreeSet<u16>,
+) -> Result<u16> {
+    if service_config.port != 0 {
+        return Ok(service_config.port);
+    }
+    let in_range = |port: &u16| {
+        service_config
+            .port_range
+            .is_none_or(|(low, high)| (low..=high).contains(port))
+    };
+    if let Some(port) = remembered.filter(|port| in_range(port) && !taken.contains(port)) {
+        return Ok(port);
+    }
+    let udp = service_config.service_type.ends_with("_udp");
+    let port = match service_config.port_range {
+        Some((low, high)) => {
+            // Start somewhere random so entries added together don't race for one port
+            let size = u32::from(high.saturating_sub(low)) + 1;
+            let start = fastrand::u32(0..size);
+            (0..size)
+                .map(|i| low + ((start + i) % size) as u16)
+                .find(|port| *port != 0 && !taken.contains(port) && available(*port, udp))
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::AddrNotAvailable,
+                        format!("no free port between {} and {}", low, high),
+                    )
+                })
+        }
+        None => (0..RANDOM_ATTEMPTS)
+            .map(|_| available_port(udp))
+            .find(|port| !port.as_ref().is_ok_and(|port| taken.contains(port)))
+            .unwrap_or_else(|| {
+                Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    "every port picked was taken by another entry",
+                ))
+            }),
+    };
+    port.map_err(|source| Error::Port {
+        key: key.to_string(),
+        source,
+    })
+}
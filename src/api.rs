@@ -0,0 +1,252 @@
+use crate::{
+    control::{self, ControlError, Request, Response},
+    daemon::Event,
+    settings::ServiceConfig,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    io::{self, Read},
+    net::{Ipv4Addr, SocketAddr},
+    sync::{mpsc, Arc},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use tiny_http::{Header, Method, Server};
+
+// Request bodies are a service entry or a TXT record; anything bigger is a mistake.
+const MAX_BODY: u64 = 64 * 1024;
+// How long stopping waits for a request in progress before leaving the thread behind.
+const STOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+// [api]: a JSON API on 127.0.0.1 for adding and removing advertisements at runtime.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ApiConfig {
+    pub enabled: bool,
+    pub port: u16,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            enabled: false,
+            port: 5380,
+        }
+    }
+}
+
+// `POST /services` body: a [services.*] entry plus the key to file it under.
+#[derive(Deserialize)]
+struct NewService {
+    key: String,
+    #[serde(flatten)]
+    config: ServiceConfig,
+}
+
+// The HTTP server thread; dropping it stops the server.
+pub struct Api {
+    server: Arc<Server>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Api {
+    // Listen on the loopback interface only and hand requests to the worker loop
+    // through `events`.
+    pub fn start(config: &ApiConfig, events: mpsc::Sender<Event>) -> io::Result<Self> {
+        let address = SocketAddr::from((Ipv4Addr::LOCALHOST, config.port));
+        let server = Arc::new(Server::http(address).map_err(io::Error::other)?);
+        log::info!(address:% = address; "HTTP API listening");
+        let port = config.port;
+        let thread = thread::spawn({
+            let server = server.clone();
+            move || {
+                for request in server.incoming_requests() {
+                    handle(&events, port, request);
+                }
+            }
+        });
+        Ok(Api {
+            server,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Api {
+    fn drop(&mut self) {
+        self.server.unblock();
+        // A request can be waiting on the worker loop, which is busy shutting down
+        if let Some(thread) = self.thread.take() {
+            let deadline = Instant::now() + STOP_TIMEOUT;
+            while !thread.is_finished() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+            if thread.is_finished() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+fn handle(events: &mpsc::Sender<Event>, port: u16, mut request: tiny_http::Request) {
+    let mut body = String::new();
+    let (status, value) = if let Some(refused) = refuse(&request, port) {
+        refused
+    } else {
+        match request.as_reader().take(MAX_BODY).read_to_string(&mut body) {
+            Ok(_) => route(events, request.method(), request.url(), &body),
+            Err(e) => (400, Some(json!({ "error": e.to_string() }))),
+        }
+    };
+    log::debug!(method:% = request.method(), url = request.url(), status; "API request");
+    let response = match value {
+        Some(value) => tiny_http::Response::from_string(value.to_string())
+            .with_header(
+                Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+                    .expect("static header"),
+            )
+            .with_status_code(status)
+            .boxed(),
+        None => tiny_http::Response::empty(status).boxed(),
+    };
+    if let Err(e) = request.respond(response) {
+        log::warn!(error:% = e; "Error answering API request");
+    }
+}
+
+// Web pages the local user opens can reach 127.0.0.1 too. Browsers send Origin with
+// cross-origin requests, a Host of their own after DNS rebinding, and can only send a
+// JSON Content-Type after a CORS preflight, which is never answered here.
+fn refuse(request: &tiny_http::Request, port: u16) -> Option<(u16, Option<Value>)> {
+    let header = |name: &'static str| {
+        request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv(name))
+            .map(|h| h.value.as_str())
+    };
+    let forbidden = |message: &str| Some((403, Some(json!({ "error": message }))));
+    if header("Origin").is_some() {
+        return forbidden("requests from web pages are not allowed");
+    }
+    let hosts = [format!("127.0.0.1:{}", port), format!("localhost:{}", port)];
+    if !header("Host").is_some_and(|host| hosts.iter().any(|h| h.eq_ignore_ascii_case(host))) {
+        return forbidden("Host must be 127.0.0.1 or localhost with the API port");
+    }
+    let json = header("Content-Type").is_some_and(|content_type| {
+        content_type
+            .split(';')
+            .next()
+            .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/json"))
+    });
+    if matches!(request.method(), Method::Post | Method::Put) && !json {
+        return Some((
+            415,
+            Some(json!({ "error": "Content-Type must be application/json" })),
+        ));
+    }
+    None
+}
+
+// Map a request onto a control::Request, returning the status code and JSON body.
+fn route(
+    events: &mpsc::Sender<Event>,
+    method: &Method,
+    url: &str,
+    body: &str,
+) -> (u16, Option<Value>) {
+    let path = url.split('?').next().unwrap_or_default();
+    let segments: Vec<String> = path
+        .trim_matches('/')
+        .split('/')
+        .map(percent_decode)
+        .collect();
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    let request = match (method, &segments[..]) {
+        (Method::Get, ["services"]) => Request::List,
+        (Method::Post, ["services"]) => match serde_json::from_str::<NewService>(body) {
+            Ok(new) => Request::Add {
+                key: new.key,
+                config: Box::new(new.config),
+            },
+            Err(e) => return bad_request(e),
+        },
+        (Method::Get, ["services", key]) => Request::Get {
+            key: key.to_string(),
+        },
+        (Method::Delete, ["services", key]) => Request::Remove {
+            key: key.to_string(),
+        },
+        (Method::Put, ["services", key, "txt"]) => {
+            match serde_json::from_str::<HashMap<String, String>>(body) {
+                Ok(text) => Request::UpdateTxt {
+                    key: key.to_string(),
+                    text,
+                },
+                Err(e) => return bad_request(e),
+            }
+        }
+        (Method::Post, ["groups", group, action @ ("enable" | "disable")]) => Request::SetGroup {
+            group: group.to_string(),
+            enabled: *action == "enable",
+        },
+        (
+            _,
+            ["services"]
+            | ["services", _]
+            | ["services", _, "txt"]
+            | ["groups", _, "enable" | "disable"],
+        ) => return (405, Some(json!({ "error": "method not allowed" }))),
+        _ => return (404, Some(json!({ "error": "not found" }))),
+    };
+    let created = matches!(request, Request::Add { .. });
+    match control::send(events, request) {
+        Ok(Response::Services(services)) => (200, Some(json!(services))),
+        Ok(Response::Service(service)) => (if created { 201 } else { 200 }, Some(json!(service))),
+        Ok(Response::Removed) => (204, None),
+        Err(e) => {
+            let status = match e {
+                ControlError::NotFound(_) | ControlError::NoGroup(_) => 404,
+                ControlError::Exists(_) => 409,
+                ControlError::Invalid(_) => 400,
+                ControlError::Failed(_) => 500,
+                ControlError::Unavailable => 503,
+            };
+            let mut body = json!({ "error": e.to_string() });
+            if let ControlError::Invalid(diagnostics) = e {
+                body["diagnostics"] = json!(diagnostics);
+            }
+            (status, Some(body))
+        }
+    }
+}
+
+fn bad_request(e: serde_json::Error) -> (u16, Option<Value>) {
+    (400, Some(json!({ "error": e.to_string() })))
+}
+
+// Keys are TOML table names and may need escaping in a URL.
+fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
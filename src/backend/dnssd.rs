@@ -0,0 +1,80 @@
+use super::{Advertisement, Backend, Discovered};
+use crate::error::{Error, Result};
+use astro_dnssd::{
+    DNSServiceBuilder, RegisteredDnsService, ServiceBrowserBuilder, ServiceEventType,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+// astro-dnssd, i.e. the system's dns_sd library.
+#[derive(Default)]
+pub struct DnssdBackend {
+    registered: BTreeMap<String, (Advertisement, RegisteredDnsService)>,
+}
+
+impl Backend for DnssdBackend {
+    fn register(&mut self, key: &str, advertisement: &Advertisement) -> Result<()> {
+        // Drop any previous registration first so the name is free again
+        self.registered.remove(key);
+        let service = DNSServiceBuilder::new(&advertisement.service_type, advertisement.port)
+            .with_name(&advertisement.name)
+            .with_txt_record(advertisement.text.clone())
+            .register()
+            .map_err(|e| Error::Registration {
+                key: key.to_string(),
+                message: format!("{:?}", e),
+            })?;
+        self.registered
+            .insert(key.to_string(), (advertisement.clone(), service));
+        Ok(())
+    }
+
+    // astro-dnssd has no DNSServiceUpdateRecord, so the TXT record is replaced by
+    // registering again.
+    fn update_txt(&mut self, key: &str, text: &HashMap<String, String>) -> Result<()> {
+        let Some((advertisement, _)) = self.registered.get(key) else {
+            return Err(Error::Registration {
+                key: key.to_string(),
+                message: "not registered".into(),
+            });
+        };
+        let advertisement = Advertisement {
+            text: text.clone(),
+            ..advertisement.clone()
+        };
+        self.register(key, &advertisement)
+    }
+
+    fn unregister(&mut self, key: &str) {
+        self.registered.remove(key);
+    }
+
+    fn browse(&mut self, service_type: &str, timeout: Duration) -> Result<Vec<Discovered>> {
+        let browser = ServiceBrowserBuilder::new(service_type)
+            .browse()
+            .map_err(|e| Error::Browse {
+                service_type: service_type.to_string(),
+                message: format!("{:?}", e),
+            })?;
+        let deadline = Instant::now() + timeout;
+        let mut found = Vec::new();
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            let Ok(service) = browser.recv_timeout(remaining) else {
+                break;
+            };
+            match service.action {
+                ServiceEventType::Added => found.push(Discovered {
+                    name: service.name,
+                    service_type: service.regtype,
+                    hostname: service.hostname,
+                    port: service.port,
+                    text: service.txt_record.unwrap_or_default(),
+                }),
+                ServiceEventType::Removed => found.retain(|d| d.name != service.name),
+            }
+        }
+        Ok(found)
+    }
+}
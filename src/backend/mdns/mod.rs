@@ -0,0 +1,1037 @@
+// A small multicast DNS responder (RFC 6762) publishing DNS-SD records (RFC 6763)
+// without Bonjour or Avahi.
+//
+// Each advertisement is probed three times, announced twice and then answered for;
+// unregistering sends goodbye packets (TTL 0). Simultaneous-probe tie-breaking
+// (RFC 6762 §8.2) is not implemented: any conflicting answer counts as a conflict.
+use super::{Advertisement, Backend, Discovered, RegistrationState};
+use crate::{
+    error::{Error, Result},
+    host, metrics,
+};
+use packet::{escape_label, Message, Question, RData, Record};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{Arc, Mutex, MutexGuard},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+mod packet;
+
+const MDNS_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+const SERVICES_META: &str = "_services._dns-sd._udp.local.";
+
+// RFC 6762 §10: records naming a host get 120 s, everything else 75 minutes
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+// Legacy unicast answers must not be cached for long (RFC 6762 §6.7)
+const LEGACY_TTL: u32 = 10;
+
+const PROBE_COUNT: u8 = 3;
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+// RFC 6762 §8.1: the first probe waits a random 0-250ms, so hosts starting together
+// don't probe in lockstep
+const PROBE_DELAY_MS: u64 = 250;
+const ANNOUNCE_COUNT: u8 = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+// How often the socket threads wake up to send scheduled probes and announcements
+const TICK: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MdnsConfig {
+    // Host name published in SRV and address records, without ".local"
+    pub hostname: Option<String>,
+    // IPv4 address of the interface to use; all interfaces when unset. Setting it
+    // to 127.0.0.1 keeps everything on loopback, which is handy for testing.
+    pub interface: Option<Ipv4Addr>,
+    pub ipv6: bool,
+    // Anything but 5353 is only useful for testing
+    pub port: u16,
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        MdnsConfig {
+            hostname: None,
+            interface: None,
+            ipv6: true,
+            port: 5353,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Probing(u8),
+    Announcing(u8),
+    Established,
+    Conflict,
+}
+
+struct Instance {
+    advertisement: Advertisement,
+    // Fully qualified instance name, e.g. `MacPro._smb._tcp.local.`
+    fqdn: String,
+    // The advertisement's interface address and its netmask
+    network: Option<(Ipv4Addr, Ipv4Addr)>,
+    phase: Phase,
+    next: Instant,
+}
+
+impl Instance {
+    fn new(advertisement: &Advertisement, network: Option<(Ipv4Addr, Ipv4Addr)>) -> Self {
+        Instance {
+            fqdn: instance_name(advertisement),
+            advertisement: advertisement.clone(),
+            network,
+            phase: Phase::Probing(0),
+            next: Instant::now() + Duration::from_millis(fastrand::u64(0..=PROBE_DELAY_MS)),
+        }
+    }
+
+    // The advertisement's own TTL, if it has one, instead of `default`.
+    fn ttl(&self, default: u32) -> u32 {
+        self.advertisement.ttl.unwrap_or(default)
+    }
+
+    // Whether a query from `source` gets answers about this instance: with an interface
+    // set, only queries from that interface's network do.
+    fn reachable_from(&self, source: IpAddr) -> bool {
+        let Some(network) = self.network else {
+            return true;
+        };
+        matches!(source, IpAddr::V4(source) if on_network(source, network))
+    }
+
+    // The interface address its packets go out of, if it has one.
+    fn interface(&self) -> Option<Ipv4Addr> {
+        self.network.map(|(address, _)| address)
+    }
+
+    fn service_domain(&self) -> String {
+        format!(
+            "{}.local.",
+            self.advertisement.service_type.trim_end_matches('.')
+        )
+    }
+
+    fn answering(&self) -> bool {
+        matches!(self.phase, Phase::Announcing(_) | Phase::Established)
+    }
+}
+
+fn instance_name(advertisement: &Advertisement) -> String {
+    format!(
+        "{}.{}.local.",
+        escape_label(&advertisement.name),
+        advertisement.service_type.trim_end_matches('.')
+    )
+}
+
+struct State {
+    // `<hostname>.local.`
+    host: String,
+    addresses: Vec<IpAddr>,
+    instances: BTreeMap<String, Instance>,
+    stopped: bool,
+}
+
+impl State {
+    fn address_records(&self, ttl: u32) -> Vec<Record> {
+        self.records_for(&self.addresses, ttl)
+    }
+
+    // The address records that go with one instance: only its interface's, if it has one.
+    fn instance_address_records(&self, instance: &Instance) -> Vec<Record> {
+        match instance.network {
+            Some((address, _)) => self.records_for(&[IpAddr::V4(address)], HOST_TTL),
+            None => self.address_records(HOST_TTL),
+        }
+    }
+
+    fn records_for(&self, addresses: &[IpAddr], ttl: u32) -> Vec<Record> {
+        addresses
+            .iter()
+            .map(|address| {
+                let data = match address {
+                    IpAddr::V4(v4) => RData::A(*v4),
+                    IpAddr::V6(v6) => RData::Aaaa(*v6),
+                };
+                Record::new(&self.host, ttl, data)
+            })
+            .collect()
+    }
+
+    fn srv(&self, instance: &Instance, ttl: u32) -> Record {
+        Record::new(
+            &instance.fqdn,
+            ttl,
+            RData::Srv {
+                priority: 0,
+                weight: 0,
+                port: instance.advertisement.port,
+                target: self.host.clone(),
+            },
+        )
+    }
+
+    fn txt(&self, instance: &Instance, ttl: u32) -> Record {
+        let mut text: Vec<_> = instance.advertisement.text.iter().collect();
+        text.sort();
+        let strings = text
+            .into_iter()
+            .map(|(key, value)| format!("{}={}", key, value).into_bytes())
+            .collect();
+        Record::new(&instance.fqdn, ttl, RData::Txt(strings))
+    }
+
+    fn ptr(&self, instance: &Instance, ttl: u32) -> Record {
+        Record::new(
+            &instance.service_domain(),
+            ttl,
+            RData::Ptr(instance.fqdn.clone()),
+        )
+    }
+
+    fn meta_ptr(&self, instance: &Instance, ttl: u32) -> Record {
+        Record::new(SERVICES_META, ttl, RData::Ptr(instance.service_domain()))
+    }
+
+    // Every record of one instance, as announced (or retracted with ttl = 0).
+    fn announcement(&self, instance: &Instance, goodbye: bool) -> Message {
+        let ttl = |ttl| if goodbye { 0 } else { ttl };
+        let mut message = Message::response();
+        message.answers = vec![
+            self.ptr(instance, ttl(instance.ttl(SERVICE_TTL))),
+            self.srv(instance, ttl(instance.ttl(HOST_TTL))),
+            self.txt(instance, ttl(instance.ttl(SERVICE_TTL))),
+        ];
+        if !goodbye {
+            message.answers.push(self.meta_ptr(instance, SERVICE_TTL));
+            message
+                .answers
+                .extend(self.instance_address_records(instance));
+        }
+        message
+    }
+
+    // TTL=0 records for the SRV and TXT data `old` advertised and `new`, with the same
+    // name, no longer does, so peers drop it now rather than when it expires.
+    fn replaced(&self, old: &Instance, new: &Instance) -> Message {
+        let current = [self.srv(new, 0), self.txt(new, 0)];
+        let mut message = Message::response();
+        message.answers = [self.srv(old, 0), self.txt(old, 0)]
+            .into_iter()
+            .filter(|record| !current.contains(record))
+            .collect();
+        message
+    }
+
+    fn probe(&self, instance: &Instance) -> Message {
+        let mut message = Message::query();
+        message.questions.push(Question {
+            name: instance.fqdn.clone(),
+            qtype: packet::TYPE_ANY,
+            unicast_response: true,
+        });
+        message.authorities = vec![
+            self.srv(instance, instance.ttl(HOST_TTL)),
+            self.txt(instance, instance.ttl(SERVICE_TTL)),
+        ];
+        message
+    }
+
+    // Answers and additional records for one question asked by `source`.
+    fn answer(
+        &self,
+        question: &Question,
+        source: IpAddr,
+        answers: &mut Vec<Record>,
+        additionals: &mut Vec<Record>,
+    ) {
+        let name = question.name.as_str();
+        let wants = |qtype| question.qtype == qtype || question.qtype == packet::TYPE_ANY;
+        for instance in self
+            .instances
+            .values()
+            .filter(|i| i.answering() && i.reachable_from(source))
+        {
+            if name.eq_ignore_ascii_case(SERVICES_META) && wants(packet::TYPE_PTR) {
+                answers.push(self.meta_ptr(instance, SERVICE_TTL));
+            }
+            if name.eq_ignore_ascii_case(&instance.service_domain()) && wants(packet::TYPE_PTR) {
+                answers.push(self.ptr(instance, instance.ttl(SERVICE_TTL)));
+                additionals.push(self.srv(instance, instance.ttl(HOST_TTL)));
+                additionals.push(self.txt(instance, instance.ttl(SERVICE_TTL)));
+                additionals.extend(self.instance_address_records(instance));
+            }
+            if name.eq_ignore_ascii_case(&instance.fqdn) {
+                if wants(packet::TYPE_SRV) {
+                    answers.push(self.srv(instance, instance.ttl(HOST_TTL)));
+                    additionals.extend(self.instance_address_records(instance));
+                }
+                if wants(packet::TYPE_TXT) {
+                    answers.push(self.txt(instance, instance.ttl(SERVICE_TTL)));
+                }
+            }
+        }
+        if name.eq_ignore_ascii_case(&self.host) {
+            let records = self.address_records(HOST_TTL);
+            answers.extend(records.into_iter().filter(|r| wants(r.data.rtype())));
+        }
+    }
+
+    fn respond(&self, query: &Message, source: IpAddr) -> Option<Message> {
+        let mut answers = Vec::new();
+        let mut additionals = Vec::new();
+        for question in &query.questions {
+            self.answer(question, source, &mut answers, &mut additionals);
+        }
+        // Known-answer suppression (RFC 6762 §7.1)
+        answers.retain(|ours| {
+            !query
+                .answers
+                .iter()
+                .any(|known| known.same_as(ours) && known.ttl >= ours.ttl / 2)
+        });
+        dedup(&mut answers);
+        dedup(&mut additionals);
+        additionals.retain(|extra| !answers.iter().any(|a| a.same_as(extra)));
+        if answers.is_empty() {
+            return None;
+        }
+        let mut response = Message::response();
+        response.answers = answers;
+        response.additionals = additionals;
+        Some(response)
+    }
+
+    // A response from someone else claiming one of our unique records with other data.
+    fn check_conflicts(&mut self, response: &Message) {
+        let host = self.host.clone();
+        for instance in self.instances.values_mut() {
+            if instance.phase == Phase::Conflict {
+                continue;
+            }
+            let conflicting = response
+                .answers
+                .iter()
+                .chain(&response.additionals)
+                .filter(|r| r.name.eq_ignore_ascii_case(&instance.fqdn))
+                .any(|r| match &r.data {
+                    RData::Srv { port, target, .. } => {
+                        *port != instance.advertisement.port || !target.eq_ignore_ascii_case(&host)
+                    }
+                    _ => false,
+                });
+            if conflicting {
+                log::warn!(instance = instance.fqdn.as_str(); "Name conflict, another host already uses it");
+                instance.phase = Phase::Conflict;
+            }
+        }
+    }
+}
+
+fn dedup(records: &mut Vec<Record>) {
+    let mut unique: Vec<Record> = Vec::with_capacity(records.len());
+    for record in records.drain(..) {
+        if !unique.iter().any(|u| u.same_as(&record)) {
+            unique.push(record);
+        }
+    }
+    *records = unique;
+}
+
+// An interface the responder joined the group on and sends out of.
+enum Link {
+    // The interface's addresses and netmasks; the first address picks the interface
+    V4(Vec<(Ipv4Addr, Ipv4Addr)>),
+    // The interface index
+    V6(u32),
+}
+
+impl Link {
+    // Whether a packet from `source` came in on this interface, as far as can be told
+    // from the address.
+    fn reaches(&self, source: SocketAddr) -> bool {
+        match (self, source) {
+            (Link::V4(networks), SocketAddr::V4(source)) => networks
+                .iter()
+                .any(|network| on_network(*source.ip(), *network)),
+            (Link::V6(index), SocketAddr::V6(source)) => source.scope_id() == *index,
+            _ => false,
+        }
+    }
+}
+
+fn on_network(address: Ipv4Addr, (network, netmask): (Ipv4Addr, Ipv4Addr)) -> bool {
+    let mask = u32::from(netmask);
+    u32::from(address) & mask == u32::from(network) & mask
+}
+
+// One socket per address family, joined to the group on each of its links.
+struct Endpoint {
+    socket: UdpSocket,
+    group: SocketAddr,
+    links: Vec<Link>,
+}
+
+struct Responder {
+    state: Mutex<State>,
+    endpoints: Vec<Endpoint>,
+    // Held while a socket's multicast interface is switched for a send
+    sending: Mutex<()>,
+    port: u16,
+}
+
+impl Responder {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Send to the group on every interface, or only out of the one with the address
+    // `interface`.
+    fn multicast(&self, message: &Message, interface: Option<Ipv4Addr>) {
+        let packet = message.encode();
+        for endpoint in &self.endpoints {
+            let links = endpoint
+                .links
+                .iter()
+                .filter(|link| match (link, interface) {
+                    (_, None) => true,
+                    (Link::V4(networks), Some(interface)) => {
+                        networks.iter().any(|(address, _)| *address == interface)
+                    }
+                    (Link::V6(_), Some(_)) => false,
+                });
+            self.send(endpoint, &packet, links);
+        }
+    }
+
+    // The address and netmask of the joined interface that has `address`.
+    fn network(&self, address: Ipv4Addr) -> Option<(Ipv4Addr, Ipv4Addr)> {
+        self.endpoints[0].links.iter().find_map(|link| match link {
+            Link::V4(networks) => networks.iter().find(|(a, _)| *a == address).copied(),
+            Link::V6(_) => None,
+        })
+    }
+
+    // Send to the group once out of each of `links`.
+    fn send<'a>(&self, endpoint: &Endpoint, packet: &[u8], links: impl Iterator<Item = &'a Link>) {
+        let _sending = self.sending.lock().unwrap_or_else(|e| e.into_inner());
+        let socket = SockRef::from(&endpoint.socket);
+        for link in links {
+            let result = match link {
+                Link::V4(networks) => socket.set_multicast_if_v4(&networks[0].0),
+                Link::V6(index) => socket.set_multicast_if_v6(*index),
+            }
+            .and_then(|()| endpoint.socket.send_to(packet, endpoint.group));
+            match result {
+                Ok(_) => metrics::packet_sent(),
+                Err(e) => {
+                    log::warn!(to:% = endpoint.group, error:% = e; "Error sending mDNS packet")
+                }
+            }
+        }
+    }
+
+    // Send whatever probes and announcements are due.
+    fn tick(&self) {
+        let now = Instant::now();
+        let mut outgoing = Vec::new();
+        {
+            let mut state = self.state();
+            let keys: Vec<String> = state.instances.keys().cloned().collect();
+            for key in keys {
+                let instance = &state.instances[&key];
+                if instance.next > now {
+                    continue;
+                }
+                let (message, phase, delay) = match instance.phase {
+                    Phase::Probing(sent) if sent < PROBE_COUNT => (
+                        state.probe(instance),
+                        Phase::Probing(sent + 1),
+                        PROBE_INTERVAL,
+                    ),
+                    Phase::Probing(_) => (
+                        state.announcement(instance, false),
+                        Phase::Announcing(1),
+                        ANNOUNCE_INTERVAL,
+                    ),
+                    Phase::Announcing(sent) if sent < ANNOUNCE_COUNT => (
+                        state.announcement(instance, false),
+                        Phase::Announcing(sent + 1),
+                        ANNOUNCE_INTERVAL,
+                    ),
+                    Phase::Announcing(_) => {
+                        if let Some(instance) = state.instances.get_mut(&key) {
+                            instance.phase = Phase::Established;
+                            log::debug!(instance = instance.fqdn.as_str(); "Announced");
+                        }
+                        continue;
+                    }
+                    Phase::Established | Phase::Conflict => continue,
+                };
+                outgoing.push((message, instance.interface()));
+                if let Some(instance) = state.instances.get_mut(&key) {
+                    instance.phase = phase;
+                    instance.next = now + delay;
+                }
+            }
+        }
+        for (message, interface) in &outgoing {
+            self.multicast(message, *interface);
+        }
+    }
+
+    fn handle(&self, endpoint: &Endpoint, packet: &[u8], source: SocketAddr) {
+        let message = match Message::decode(packet) {
+            Ok(message) => message,
+            Err(_) => return,
+        };
+        if message.is_response() {
+            self.state().check_conflicts(&message);
+            return;
+        }
+        let Some(mut response) = self.state().respond(&message, source.ip()) else {
+            return;
+        };
+        let legacy = source.port() != self.port;
+        let unicast = legacy || message.questions.iter().any(|q| q.unicast_response);
+        if legacy {
+            // RFC 6762 §6.7: echo the id and question, short TTLs, no cache-flush bits
+            response.id = message.id;
+            response.questions = message.questions.clone();
+            for record in response.answers.iter_mut().chain(&mut response.additionals) {
+                record.ttl = record.ttl.min(LEGACY_TTL);
+                record.cache_flush = false;
+            }
+        }
+        let packet = response.encode();
+        if !unicast {
+            // Back out of the interface the query came in on, or every one if unsure
+            let arrived: Vec<&Link> = endpoint
+                .links
+                .iter()
+                .filter(|l| l.reaches(source))
+                .collect();
+            if arrived.is_empty() {
+                self.send(endpoint, &packet, endpoint.links.iter());
+            } else {
+                self.send(endpoint, &packet, arrived.into_iter());
+            }
+            return;
+        }
+        match endpoint.socket.send_to(&packet, source) {
+            Ok(_) => metrics::packet_sent(),
+            Err(e) => log::warn!(to:% = source, error:% = e; "Error answering mDNS query"),
+        }
+    }
+
+    fn serve(&self, index: usize) {
+        let endpoint = &self.endpoints[index];
+        let mut buffer = [0; 9000];
+        while !self.state().stopped {
+            match endpoint.socket.recv_from(&mut buffer) {
+                Ok((len, source)) => {
+                    metrics::packet_received();
+                    self.handle(endpoint, &buffer[..len], source)
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(e) => log::warn!(error:% = e; "Error receiving mDNS packet"),
+            }
+            self.tick();
+        }
+    }
+}
+
+fn socket_v4(port: u16) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Share 5353 with any other responder on this machine
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_read_timeout(Some(TICK))?;
+    Ok(socket)
+}
+
+fn socket_v6(port: u16) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    socket.set_multicast_hops_v6(255)?;
+    socket.set_multicast_loop_v6(true)?;
+    socket.set_read_timeout(Some(TICK))?;
+    Ok(socket)
+}
+
+// Join the group on each link, keeping the links that worked.
+fn join(socket: Socket, group: SocketAddr, links: Vec<Link>) -> io::Result<Endpoint> {
+    let links: Vec<Link> = links
+        .into_iter()
+        .filter(|link| {
+            let joined = match link {
+                Link::V4(networks) => socket.join_multicast_v4(&MDNS_V4, &networks[0].0),
+                Link::V6(index) => socket.join_multicast_v6(&MDNS_V6, *index),
+            };
+            if let Err(e) = &joined {
+                match link {
+                    Link::V4(networks) => {
+                        log::warn!(interface:% = networks[0].0, error:% = e; "Can't join the mDNS group")
+                    }
+                    Link::V6(index) => {
+                        log::warn!(interface = index, error:% = e; "Can't join the mDNS group")
+                    }
+                }
+            }
+            joined.is_ok()
+        })
+        .collect();
+    if links.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "can't join the mDNS group on any interface",
+        ));
+    }
+    Ok(Endpoint {
+        socket: socket.into(),
+        group,
+        links,
+    })
+}
+
+// The interfaces to run on: the configured one, or every one but loopback. IPv4
+// addresses are grouped by interface so each is joined and sent out of once. Without
+// any, the system's default interface is used.
+fn links(config: &MdnsConfig) -> (Vec<Link>, Vec<Link>) {
+    let interfaces = if_addrs::get_if_addrs().unwrap_or_else(|e| {
+        log::error!(error:% = e; "Error listing network interfaces");
+        Vec::new()
+    });
+    let mut v4: Vec<(&str, Vec<(Ipv4Addr, Ipv4Addr)>)> = Vec::new();
+    let mut indexes = Vec::new();
+    for interface in &interfaces {
+        if let if_addrs::IfAddr::V4(address) = &interface.addr {
+            let network = (address.ip, address.netmask);
+            match v4.iter_mut().find(|(name, _)| *name == interface.name) {
+                Some((_, networks)) => networks.push(network),
+                None => v4.push((&interface.name, vec![network])),
+            }
+        }
+        // if_addrs leaves link-local IPv6 addresses out, so an interface listed with any
+        // address gets IPv6 too
+        if !interface.is_loopback() {
+            if let Some(index) = host::interface_index(&interface.name) {
+                if !indexes.contains(&index) {
+                    indexes.push(index);
+                }
+            }
+        }
+    }
+    let mut v4: Vec<Link> = match config.interface {
+        Some(interface) => {
+            let mut networks = v4
+                .into_iter()
+                .map(|(_, networks)| networks)
+                .find(|networks| networks.iter().any(|(address, _)| *address == interface))
+                .unwrap_or_else(|| vec![(interface, Ipv4Addr::BROADCAST)]);
+            // The configured address picks the interface
+            networks.sort_by_key(|(address, _)| *address != interface);
+            vec![Link::V4(networks)]
+        }
+        None => v4
+            .into_iter()
+            .filter(|(_, networks)| !networks.iter().any(|(address, _)| address.is_loopback()))
+            .map(|(_, networks)| Link::V4(networks))
+            .collect(),
+    };
+    if v4.is_empty() {
+        v4.push(Link::V4(vec![(
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::UNSPECIFIED,
+        )]));
+    }
+    let mut v6: Vec<Link> = indexes.into_iter().map(Link::V6).collect();
+    if v6.is_empty() {
+        v6.push(Link::V6(0));
+    }
+    (v4, v6)
+}
+
+// Addresses published for the host name: the configured interface only, or every
+// non-loopback address.
+fn local_addresses(config: &MdnsConfig) -> Vec<IpAddr> {
+    if let Some(interface) = config.interface {
+        return vec![IpAddr::V4(interface)];
+    }
+    match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces
+            .into_iter()
+            .filter(|i| !i.is_loopback())
+            .map(|i| i.ip())
+            .filter(|ip| config.ipv6 || ip.is_ipv4())
+            .collect(),
+        Err(e) => {
+            log::error!(error:% = e; "Error listing network interfaces");
+            Vec::new()
+        }
+    }
+}
+
+pub struct MdnsBackend {
+    responder: Arc<Responder>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl MdnsBackend {
+    pub fn start(config: &MdnsConfig) -> io::Result<Self> {
+        let (v4, v6) = links(config);
+        let mut endpoints = vec![join(
+            socket_v4(config.port)?,
+            SocketAddr::from((MDNS_V4, config.port)),
+            v4,
+        )?];
+        if config.ipv6 && config.interface.is_none() {
+            let group = SocketAddr::from((MDNS_V6, config.port));
+            match socket_v6(config.port).and_then(|socket| join(socket, group, v6)) {
+                Ok(endpoint) => endpoints.push(endpoint),
+                Err(e) => log::warn!(error:% = e; "IPv6 mDNS disabled"),
+            }
+        }
+        let hostname = config.hostname.clone().unwrap_or_else(host::hostname);
+        let responder = Arc::new(Responder {
+            state: Mutex::new(State {
+                host: format!("{}.local.", escape_label(&hostname)),
+                addresses: local_addresses(config),
+                instances: BTreeMap::new(),
+                stopped: false,
+            }),
+            sending: Mutex::new(()),
+            port: config.port,
+            endpoints,
+        });
+        let threads = (0..responder.endpoints.len())
+            .map(|index| {
+                let responder = responder.clone();
+                std::thread::spawn(move || responder.serve(index))
+            })
+            .collect();
+        Ok(MdnsBackend { responder, threads })
+    }
+
+    fn goodbye(&self, state: &State, instance: &Instance) {
+        if instance.answering() {
+            self.responder
+                .multicast(&state.announcement(instance, true), instance.interface());
+        }
+    }
+}
+
+impl Backend for MdnsBackend {
+    fn register(&mut self, key: &str, advertisement: &Advertisement) -> Result<()> {
+        let network = match advertisement.interface {
+            Some(interface) => {
+                Some(
+                    self.responder
+                        .network(interface)
+                        .ok_or_else(|| Error::Registration {
+                            key: key.to_string(),
+                            message: format!(
+                                "no interface the responder runs on has the address {}",
+                                interface
+                            ),
+                        })?,
+                )
+            }
+            None => None,
+        };
+        let mut state = self.responder.state();
+        let instance = Instance::new(advertisement, network);
+        if let Some(old) = state.instances.get(key) {
+            // Same name: keep answering, withdraw the data that changed and announce
+            // the new data right away
+            if old.fqdn.eq_ignore_ascii_case(&instance.fqdn) && old.answering() {
+                let replaced = state.replaced(old, &instance);
+                if !replaced.answers.is_empty() {
+                    self.responder.multicast(&replaced, old.interface());
+                }
+                let mut instance = instance;
+                instance.phase = Phase::Announcing(0);
+                instance.next = Instant::now();
+                state.instances.insert(key.to_string(), instance);
+                return Ok(());
+            }
+            self.goodbye(&state, old);
+        }
+        state.instances.insert(key.to_string(), instance);
+        Ok(())
+    }
+
+    fn update_txt(&mut self, key: &str, text: &HashMap<String, String>) -> Result<()> {
+        let mut state = self.responder.state();
+        let Some(instance) = state.instances.get_mut(key) else {
+            return Err(Error::Registration {
+                key: key.to_string(),
+                message: "not registered".into(),
+            });
+        };
+        instance.advertisement.text = text.clone();
+        // RFC 6762 §8.4: announce the changed record again
+        if instance.answering() {
+            instance.phase = Phase::Announcing(0);
+            instance.next = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn unregister(&mut self, key: &str) {
+        let mut state = self.responder.state();
+        if let Some(instance) = state.instances.remove(key) {
+            self.goodbye(&state, &instance);
+        }
+    }
+
+    fn state(&self, key: &str) -> RegistrationState {
+        match self.responder.state().instances.get(key).map(|i| i.phase) {
+            Some(Phase::Announcing(_) | Phase::Established) => RegistrationState::Registered,
+            Some(Phase::Conflict) => RegistrationState::Conflicted,
+            Some(Phase::Probing(_)) | None => RegistrationState::Pending,
+        }
+    }
+
+    // Sends one PTR query from an ephemeral port, so responders answer by unicast.
+    fn browse(&mut self, service_type: &str, timeout: Duration) -> Result<Vec<Discovered>> {
+        let browse_error = |e: io::Error| Error::Browse {
+            service_type: service_type.to_string(),
+            message: e.to_string(),
+        };
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).map_err(browse_error)?;
+        let mut query = Message::query();
+        query.questions.push(Question {
+            name: format!("{}.local.", service_type.trim_end_matches('.')),
+            qtype: packet::TYPE_PTR,
+            unicast_response: false,
+        });
+        let packet = query.encode();
+        let endpoint = &self.responder.endpoints[0];
+        for link in &endpoint.links {
+            if let Link::V4(networks) = link {
+                SockRef::from(&socket)
+                    .set_multicast_if_v4(&networks[0].0)
+                    .and_then(|()| socket.send_to(&packet, endpoint.group))
+                    .map_err(browse_error)?;
+            }
+        }
+        let deadline = Instant::now() + timeout;
+        let mut records = Vec::new();
+        let mut buffer = [0; 9000];
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            socket
+                .set_read_timeout(Some(remaining.max(Duration::from_millis(1))))
+                .map_err(browse_error)?;
+            let Ok((len, _)) = socket.recv_from(&mut buffer) else {
+                continue;
+            };
+            if let Ok(message) = Message::decode(&buffer[..len]) {
+                records.extend(message.answers);
+                records.extend(message.additionals);
+            }
+        }
+        Ok(discovered(service_type, &records))
+    }
+}
+
+fn discovered(service_type: &str, records: &[Record]) -> Vec<Discovered> {
+    let mut found: Vec<Discovered> = Vec::new();
+    // Full names seen, as every interface can bring the same answer
+    let mut seen: Vec<&str> = Vec::new();
+    for record in records {
+        let RData::Ptr(fqdn) = &record.data else {
+            continue;
+        };
+        if seen.iter().any(|s| s.eq_ignore_ascii_case(fqdn)) {
+            continue;
+        }
+        seen.push(fqdn);
+        let mut discovered = Discovered {
+            name: fqdn.clone(),
+            service_type: service_type.to_string(),
+            hostname: String::new(),
+            port: 0,
+            text: HashMap::new(),
+        };
+        for record in records.iter().filter(|r| r.name.eq_ignore_ascii_case(fqdn)) {
+            match &record.data {
+                RData::Srv { port, target, .. } => {
+                    discovered.port = *port;
+                    discovered.hostname = target.clone();
+                }
+                RData::Txt(strings) => {
+                    for string in strings.iter().filter(|s| !s.is_empty()) {
+                        let string = String::from_utf8_lossy(string);
+                        let (key, value) = string.split_once('=').unwrap_or((&string, ""));
+                        discovered.text.insert(key.to_string(), value.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+        // Show the instance label rather than the full name
+        let suffix = format!(".{}.local.", service_type.trim_end_matches('.'));
+        if let Some(len) = discovered.name.len().checked_sub(suffix.len()) {
+            if discovered.name[len..].eq_ignore_ascii_case(&suffix) {
+                discovered.name.truncate(len);
+                discovered.name = discovered.name.replace("\\.", ".").replace("\\\\", "\\");
+            }
+        }
+        found.push(discovered);
+    }
+    found
+}
+
+impl Drop for MdnsBackend {
+    fn drop(&mut self) {
+        {
+            let mut state = self.responder.state();
+            for instance in state.instances.values() {
+                self.goodbye(&state, instance);
+            }
+            state.instances.clear();
+            state.stopped = true;
+        }
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> State {
+        let advertisement = Advertisement {
+            name: "Web".into(),
+            service_type: "_http._tcp".into(),
+            port: 80,
+            text: HashMap::new(),
+            interface: None,
+            ttl: None,
+        };
+        let mut instance = Instance::new(&advertisement, None);
+        instance.phase = Phase::Established;
+        State {
+            host: "host.local.".into(),
+            addresses: vec![IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))],
+            instances: BTreeMap::from([("web".to_string(), instance)]),
+            stopped: false,
+        }
+    }
+
+    fn browse(known: Vec<Record>) -> Message {
+        let mut query = Message::query();
+        query.questions.push(Question {
+            name: "_http._tcp.local.".into(),
+            qtype: packet::TYPE_PTR,
+            unicast_response: false,
+        });
+        query.answers = known;
+        query
+    }
+
+    fn ptr(ttl: u32) -> Record {
+        Record::new(
+            "_http._tcp.local.",
+            ttl,
+            RData::Ptr("Web._http._tcp.local.".into()),
+        )
+    }
+
+    const SOURCE: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 9));
+
+    #[test]
+    fn answers_browse() {
+        let response = state().respond(&browse(Vec::new()), SOURCE).unwrap();
+        assert_eq!(response.answers, [ptr(SERVICE_TTL)]);
+        let types: Vec<u16> = response
+            .additionals
+            .iter()
+            .map(|r| r.data.rtype())
+            .collect();
+        assert_eq!(types, [packet::TYPE_SRV, packet::TYPE_TXT, packet::TYPE_A]);
+    }
+
+    #[test]
+    fn known_answers_are_suppressed() {
+        // RFC 6762 §7.1: known with at least half the TTL left, or it's answered again
+        let state = state();
+        assert!(state
+            .respond(&browse(vec![ptr(SERVICE_TTL)]), SOURCE)
+            .is_none());
+        assert!(state
+            .respond(&browse(vec![ptr(SERVICE_TTL / 2)]), SOURCE)
+            .is_none());
+        let stale = state.respond(&browse(vec![ptr(SERVICE_TTL / 2 - 1)]), SOURCE);
+        assert_eq!(stale.unwrap().answers, [ptr(SERVICE_TTL)]);
+        // Another instance of the type doesn't count
+        let mut other = ptr(SERVICE_TTL);
+        other.data = RData::Ptr("Other._http._tcp.local.".into());
+        assert!(state.respond(&browse(vec![other]), SOURCE).is_some());
+    }
+
+    #[test]
+    fn no_answer_while_probing() {
+        let mut state = state();
+        state.instances.get_mut("web").unwrap().phase = Phase::Probing(1);
+        assert!(state.respond(&browse(Vec::new()), SOURCE).is_none());
+    }
+    #[test]
+    fn first_probe_waits_up_to_250ms() {
+        let before = Instant::now();
+        let instance = Instance::new(&state().instances["web"].advertisement, None);
+        assert_eq!(instance.phase, Phase::Probing(0));
+        assert!(instance.next >= before);
+        assert!(instance.next <= Instant::now() + Duration::from_millis(PROBE_DELAY_MS));
+    }
+
+    #[test]
+    fn replacing_withdraws_changed_records() {
+        let state = state();
+        let old = &state.instances["web"];
+        let same = Instance::new(&old.advertisement, None);
+        assert!(state.replaced(old, &same).answers.is_empty());
+        let moved = Instance::new(
+            &Advertisement {
+                port: 8080,
+                ..old.advertisement.clone()
+            },
+            None,
+        );
+        assert_eq!(state.replaced(old, &moved).answers, [state.srv(old, 0)]);
+        let retexted = Instance::new(
+            &Advertisement {
+                text: HashMap::from([("v".to_string(), "2".to_string())]),
+                ..old.advertisement.clone()
+            },
+            None,
+        );
+        assert_eq!(state.replaced(old, &retexted).answers, [state.txt(old, 0)]);
+    }
+}
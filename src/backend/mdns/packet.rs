@@ -0,0 +1,527 @@
+// Just enough of the DNS wire format (RFC 1035, RFC 6762 §18) for an mDNS responder.
+use std::{
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_ANY: u16 = 255;
+
+pub const CLASS_IN: u16 = 1;
+// Top bit of the class: "unicast response" in questions, "cache flush" in records
+const CLASS_FLAG: u16 = 0x8000;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+
+#[derive(Debug)]
+pub struct ParseError(&'static str);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "malformed packet: {}", self.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+    pub unicast_response: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(String),
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    Txt(Vec<Vec<u8>>),
+    Other(u16, Vec<u8>),
+}
+
+impl RData {
+    pub fn rtype(&self) -> u16 {
+        match self {
+            RData::A(_) => TYPE_A,
+            RData::Aaaa(_) => TYPE_AAAA,
+            RData::Ptr(_) => TYPE_PTR,
+            RData::Srv { .. } => TYPE_SRV,
+            RData::Txt(_) => TYPE_TXT,
+            RData::Other(rtype, _) => *rtype,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub name: String,
+    pub cache_flush: bool,
+    pub ttl: u32,
+    pub data: RData,
+}
+
+impl Record {
+    pub fn new(name: &str, ttl: u32, data: RData) -> Self {
+        // PTR records are shared; everything else we publish is unique to this host
+        let cache_flush = !matches!(data, RData::Ptr(_));
+        Record {
+            name: name.to_string(),
+            cache_flush,
+            ttl,
+            data,
+        }
+    }
+
+    // Same owner, type and data; TTL and cache-flush don't matter.
+    pub fn same_as(&self, other: &Record) -> bool {
+        self.name.eq_ignore_ascii_case(&other.name) && rdata_eq(&self.data, &other.data)
+    }
+}
+
+fn rdata_eq(a: &RData, b: &RData) -> bool {
+    match (a, b) {
+        (RData::Ptr(a), RData::Ptr(b)) => a.eq_ignore_ascii_case(b),
+        (
+            RData::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            },
+            RData::Srv {
+                priority: p,
+                weight: w,
+                port: o,
+                target: t,
+            },
+        ) => priority == p && weight == w && port == o && target.eq_ignore_ascii_case(t),
+        _ => a == b,
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Message {
+    pub id: u16,
+    pub flags: u16,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    pub authorities: Vec<Record>,
+    pub additionals: Vec<Record>,
+}
+
+impl Message {
+    pub fn query() -> Self {
+        Message::default()
+    }
+
+    pub fn response() -> Self {
+        Message {
+            flags: FLAG_RESPONSE | FLAG_AUTHORITATIVE,
+            ..Message::default()
+        }
+    }
+
+    pub fn is_response(&self) -> bool {
+        self.flags & FLAG_RESPONSE != 0
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(512);
+        for value in [
+            self.id,
+            self.flags,
+            self.questions.len() as u16,
+            self.answers.len() as u16,
+            self.authorities.len() as u16,
+            self.additionals.len() as u16,
+        ] {
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+        for question in &self.questions {
+            write_name(&mut out, &question.name);
+            out.extend_from_slice(&question.qtype.to_be_bytes());
+            let class = CLASS_IN
+                | if question.unicast_response {
+                    CLASS_FLAG
+                } else {
+                    0
+                };
+            out.extend_from_slice(&class.to_be_bytes());
+        }
+        for record in self
+            .answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals)
+        {
+            write_record(&mut out, record);
+        }
+        out
+    }
+
+    pub fn decode(packet: &[u8]) -> Result<Self, ParseError> {
+        let mut reader = Reader { packet, pos: 0 };
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let counts = [reader.u16()?, reader.u16()?, reader.u16()?, reader.u16()?];
+        let mut message = Message {
+            id,
+            flags,
+            ..Message::default()
+        };
+        for _ in 0..counts[0] {
+            let name = reader.name()?;
+            let qtype = reader.u16()?;
+            let class = reader.u16()?;
+            message.questions.push(Question {
+                name,
+                qtype,
+                unicast_response: class & CLASS_FLAG != 0,
+            });
+        }
+        for _ in 0..counts[1] {
+            message.answers.push(reader.record()?);
+        }
+        for _ in 0..counts[2] {
+            message.authorities.push(reader.record()?);
+        }
+        for _ in 0..counts[3] {
+            message.additionals.push(reader.record()?);
+        }
+        Ok(message)
+    }
+}
+
+// Names are dotted strings; a dot or backslash inside a label (instance names may
+// contain either) is escaped with a backslash.
+pub fn escape_label(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('.', "\\.")
+}
+
+fn split_labels(name: &str) -> Vec<Vec<u8>> {
+    let mut labels = Vec::new();
+    let mut label = Vec::new();
+    let mut bytes = name.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'\\' => label.extend(bytes.next()),
+            b'.' => labels.push(std::mem::take(&mut label)),
+            _ => label.push(b),
+        }
+    }
+    labels.push(label);
+    labels.retain(|label| !label.is_empty());
+    labels
+}
+
+// Names are written uncompressed; mDNS packets stay small enough.
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    for label in split_labels(name) {
+        let label = &label[..label.len().min(63)];
+        out.push(label.len() as u8);
+        out.extend_from_slice(label);
+    }
+    out.push(0);
+}
+
+fn write_record(out: &mut Vec<u8>, record: &Record) {
+    write_name(out, &record.name);
+    out.extend_from_slice(&record.data.rtype().to_be_bytes());
+    let class = CLASS_IN | if record.cache_flush { CLASS_FLAG } else { 0 };
+    out.extend_from_slice(&class.to_be_bytes());
+    out.extend_from_slice(&record.ttl.to_be_bytes());
+    let len_at = out.len();
+    out.extend_from_slice(&[0, 0]);
+    match &record.data {
+        RData::A(addr) => out.extend_from_slice(&addr.octets()),
+        RData::Aaaa(addr) => out.extend_from_slice(&addr.octets()),
+        RData::Ptr(name) => write_name(out, name),
+        RData::Srv {
+            priority,
+            weight,
+            port,
+            target,
+        } => {
+            out.extend_from_slice(&priority.to_be_bytes());
+            out.extend_from_slice(&weight.to_be_bytes());
+            out.extend_from_slice(&port.to_be_bytes());
+            write_name(out, target);
+        }
+        RData::Txt(strings) => {
+            // An empty TXT record still holds one empty string (RFC 6763 §6.1)
+            if strings.is_empty() {
+                out.push(0);
+            }
+            for string in strings {
+                let string = &string[..string.len().min(255)];
+                out.push(string.len() as u8);
+                out.extend_from_slice(string);
+            }
+        }
+        RData::Other(_, data) => out.extend_from_slice(data),
+    }
+    let len = (out.len() - len_at - 2) as u16;
+    out[len_at..len_at + 2].copy_from_slice(&len.to_be_bytes());
+}
+
+struct Reader<'a> {
+    packet: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, len: usize) -> Result<&[u8], ParseError> {
+        let end = self.pos + len;
+        let bytes = self
+            .packet
+            .get(self.pos..end)
+            .ok_or(ParseError("truncated"))?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, ParseError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, ParseError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    // Follows compression pointers; the reader ends up after the name as written.
+    fn name(&mut self) -> Result<String, ParseError> {
+        let mut labels: Vec<String> = Vec::new();
+        let mut pos = self.pos;
+        let mut resume = None;
+        for _ in 0..128 {
+            let len = *self.packet.get(pos).ok_or(ParseError("truncated name"))? as usize;
+            match len {
+                0 => {
+                    self.pos = resume.unwrap_or(pos + 1);
+                    return Ok(labels.join(".") + ".");
+                }
+                0xc0..=0xff => {
+                    let low = *self
+                        .packet
+                        .get(pos + 1)
+                        .ok_or(ParseError("truncated name"))?;
+                    resume.get_or_insert(pos + 2);
+                    pos = ((len & 0x3f) << 8) | low as usize;
+                }
+                1..=63 => {
+                    let label = self
+                        .packet
+                        .get(pos + 1..pos + 1 + len)
+                        .ok_or(ParseError("truncated label"))?;
+                    labels.push(escape_label(&String::from_utf8_lossy(label)));
+                    pos += 1 + len;
+                }
+                _ => return Err(ParseError("bad label length")),
+            }
+        }
+        Err(ParseError("compression loop"))
+    }
+
+    fn record(&mut self) -> Result<Record, ParseError> {
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        let len = self.u16()? as usize;
+        let end = self.pos + len;
+        if end > self.packet.len() {
+            return Err(ParseError("truncated record"));
+        }
+        let data = match rtype {
+            TYPE_A if len == 4 => {
+                let b = self.bytes(4)?;
+                RData::A(Ipv4Addr::new(b[0], b[1], b[2], b[3]))
+            }
+            TYPE_AAAA if len == 16 => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(self.bytes(16)?);
+                RData::Aaaa(Ipv6Addr::from(octets))
+            }
+            TYPE_PTR => RData::Ptr(self.name()?),
+            TYPE_SRV => RData::Srv {
+                priority: self.u16()?,
+                weight: self.u16()?,
+                port: self.u16()?,
+                target: self.name()?,
+            },
+            TYPE_TXT => {
+                let mut strings = Vec::new();
+                let mut rest = self.bytes(len)?;
+                while let Some((&n, tail)) = rest.split_first() {
+                    let n = (n as usize).min(tail.len());
+                    strings.push(tail[..n].to_vec());
+                    rest = &tail[n..];
+                }
+                RData::Txt(strings)
+            }
+            _ => RData::Other(rtype, self.bytes(len)?.to_vec()),
+        };
+        self.pos = end;
+        Ok(Record {
+            name,
+            cache_flush: class & CLASS_FLAG != 0,
+            ttl,
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<Record> {
+        vec![
+            Record::new(
+                "_http._tcp.local.",
+                4500,
+                RData::Ptr("Web._http._tcp.local.".into()),
+            ),
+            Record::new(
+                "Web._http._tcp.local.",
+                120,
+                RData::Srv {
+                    priority: 1,
+                    weight: 2,
+                    port: 8080,
+                    target: "host.local.".into(),
+                },
+            ),
+            Record::new(
+                "Web._http._tcp.local.",
+                4500,
+                RData::Txt(vec![b"path=/".to_vec(), b"flag".to_vec()]),
+            ),
+            Record::new("host.local.", 120, RData::A(Ipv4Addr::new(192, 0, 2, 1))),
+            Record::new("host.local.", 120, RData::Aaaa("fe80::1".parse().unwrap())),
+        ]
+    }
+
+    fn message() -> Message {
+        let mut message = Message::response();
+        message.id = 7;
+        message.questions.push(Question {
+            name: "_http._tcp.local.".into(),
+            qtype: TYPE_PTR,
+            unicast_response: true,
+        });
+        message.answers = records();
+        message.additionals = vec![Record::new("x.local.", 1, RData::Other(99, vec![1, 2, 3]))];
+        message
+    }
+
+    #[test]
+    fn round_trip() {
+        let decoded = Message::decode(&message().encode()).unwrap();
+        assert_eq!((decoded.id, decoded.flags), (7, message().flags));
+        assert!(decoded.is_response());
+        let question = &decoded.questions[0];
+        assert_eq!(
+            (
+                question.name.as_str(),
+                question.qtype,
+                question.unicast_response
+            ),
+            ("_http._tcp.local.", TYPE_PTR, true)
+        );
+        assert_eq!(decoded.answers, records());
+        assert!(decoded.authorities.is_empty());
+        assert_eq!(decoded.additionals, message().additionals);
+    }
+
+    #[test]
+    fn empty_txt_holds_one_empty_string() {
+        let mut message = Message::response();
+        message.answers = vec![Record::new("a.local.", 1, RData::Txt(Vec::new()))];
+        let decoded = Message::decode(&message.encode()).unwrap();
+        assert_eq!(decoded.answers[0].data, RData::Txt(vec![Vec::new()]));
+    }
+
+    #[test]
+    fn truncated_packets() {
+        let packet = message().encode();
+        for len in 0..packet.len() {
+            assert!(
+                Message::decode(&packet[..len]).is_err(),
+                "{} of {} bytes decoded",
+                len,
+                packet.len()
+            );
+        }
+    }
+
+    // A query header for one question, followed by `name`.
+    fn query_for(name: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        packet.extend_from_slice(name);
+        packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn compression_pointers() {
+        // The second question points back into the first one's name
+        let mut packet = query_for(b"\x03web\x05local\x00");
+        packet[5] = 2;
+        packet.extend_from_slice(b"\x01a\xc0\x10");
+        packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+        packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+        let message = Message::decode(&packet).unwrap();
+        assert_eq!(message.questions[0].name, "web.local.");
+        assert_eq!(message.questions[1].name, "a.local.");
+        assert_eq!(message.questions[1].qtype, TYPE_PTR);
+    }
+
+    #[test]
+    fn compression_loops() {
+        // Pointing at itself, and two names pointing at each other
+        let itself = query_for(b"\xc0\x0c");
+        assert_eq!(Message::decode(&itself).unwrap_err().0, "compression loop");
+        let each_other = query_for(b"\x01a\xc0\x10\x01b\xc0\x0c");
+        assert_eq!(
+            Message::decode(&each_other).unwrap_err().0,
+            "compression loop"
+        );
+        let outside = query_for(b"\xc0\xff");
+        assert_eq!(Message::decode(&outside).unwrap_err().0, "truncated name");
+    }
+
+    #[test]
+    fn bad_label_length() {
+        let packet = query_for(b"\x40x\x00");
+        assert_eq!(Message::decode(&packet).unwrap_err().0, "bad label length");
+    }
+
+    #[test]
+    fn dots_in_labels() {
+        assert_eq!(escape_label("My.Printer"), "My\\.Printer");
+        assert_eq!(escape_label("a\\b.c"), "a\\\\b\\.c");
+        let name = format!("{}._ipp._tcp.local.", escape_label("Floor 2.East"));
+        let mut message = Message::response();
+        message.answers = vec![Record::new(&name, 1, RData::Ptr(name.clone()))];
+        let packet = message.encode();
+        // One label for the instance, dot included
+        assert!(packet.windows(13).any(|w| w == b"\x0cFloor 2.East"));
+        let decoded = Message::decode(&packet).unwrap();
+        assert_eq!(decoded.answers[0].name, name);
+        assert_eq!(decoded.answers[0].data, RData::Ptr(name));
+    }
+}
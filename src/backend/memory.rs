@@ -0,0 +1,53 @@
+use super::{Advertisement, Backend, Discovered};
+use crate::error::{Error, Result};
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+// Registrations that only live in this process. Useful to exercise the reload and
+// supervisor logic without a dns_sd daemon.
+#[derive(Default)]
+pub struct MemoryBackend {
+    registered: BTreeMap<String, Advertisement>,
+}
+
+impl Backend for MemoryBackend {
+    fn register(&mut self, key: &str, advertisement: &Advertisement) -> Result<()> {
+        self.registered
+            .insert(key.to_string(), advertisement.clone());
+        Ok(())
+    }
+
+    fn update_txt(&mut self, key: &str, text: &HashMap<String, String>) -> Result<()> {
+        match self.registered.get_mut(key) {
+            Some(advertisement) => {
+                advertisement.text = text.clone();
+                Ok(())
+            }
+            None => Err(Error::Registration {
+                key: key.to_string(),
+                message: "not registered".into(),
+            }),
+        }
+    }
+
+    fn unregister(&mut self, key: &str) {
+        self.registered.remove(key);
+    }
+
+    fn browse(&mut self, service_type: &str, _timeout: Duration) -> Result<Vec<Discovered>> {
+        Ok(self
+            .registered
+            .values()
+            .filter(|a| a.service_type.eq_ignore_ascii_case(service_type))
+            .map(|a| Discovered {
+                name: a.name.clone(),
+                service_type: a.service_type.clone(),
+                hostname: "localhost".into(),
+                port: a.port,
+                text: a.text.clone(),
+            })
+            .collect())
+    }
+}
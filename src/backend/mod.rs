@@ -0,0 +1,131 @@
+use crate::{
+    error::{Error, Result},
+    settings::ServiceConfig,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, net::Ipv4Addr, time::Duration};
+
+mod dnssd;
+pub mod mdns;
+pub mod memory;
+
+// A [services.*] entry as it goes on the wire, with port 0 already resolved.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Advertisement {
+    pub name: String,
+    pub service_type: String,
+    pub port: u16,
+    pub text: HashMap<String, String>,
+    // Only the builtin backend honors these two
+    pub interface: Option<Ipv4Addr>,
+    pub ttl: Option<u32>,
+}
+
+impl Advertisement {
+    pub fn new(service_config: &ServiceConfig, port: u16) -> Self {
+        Advertisement {
+            name: service_config.name.clone(),
+            service_type: service_config.service_type.clone(),
+            port,
+            text: service_config.text.clone().unwrap_or_default(),
+            interface: service_config.interface,
+            ttl: service_config.ttl,
+        }
+    }
+}
+
+// A service instance seen on the network while browsing.
+#[derive(Debug, Clone)]
+pub struct Discovered {
+    pub name: String,
+    pub service_type: String,
+    pub hostname: String,
+    pub port: u16,
+    pub text: HashMap<String, String>,
+}
+
+// Where a registration stands. Backends never report Failed, Unhealthy, Inactive or
+// Disabled themselves; the daemon sets them when register returned an error, the health
+// check failed, the process the entry follows isn't running or the entry is switched off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegistrationState {
+    Pending,
+    Registered,
+    Failed,
+    Conflicted,
+    Unhealthy,
+    Inactive,
+    Disabled,
+}
+
+impl RegistrationState {
+    // Whether the backend holds the registration and has to be told to withdraw it.
+    pub fn in_backend(self) -> bool {
+        !matches!(
+            self,
+            RegistrationState::Failed
+                | RegistrationState::Unhealthy
+                | RegistrationState::Inactive
+                | RegistrationState::Disabled
+        )
+    }
+}
+
+impl fmt::Display for RegistrationState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            RegistrationState::Pending => "pending",
+            RegistrationState::Registered => "registered",
+            RegistrationState::Failed => "failed",
+            RegistrationState::Conflicted => "conflicted",
+            RegistrationState::Unhealthy => "unhealthy",
+            RegistrationState::Inactive => "inactive",
+            RegistrationState::Disabled => "disabled",
+        })
+    }
+}
+
+// Something that can put advertisements on the network. Registrations are keyed by
+// their [services.*] table name; registering an existing key replaces it.
+pub trait Backend: Send {
+    fn register(&mut self, key: &str, advertisement: &Advertisement) -> Result<()>;
+    fn update_txt(&mut self, key: &str, text: &HashMap<String, String>) -> Result<()>;
+    fn unregister(&mut self, key: &str);
+    fn browse(&mut self, service_type: &str, timeout: Duration) -> Result<Vec<Discovered>>;
+
+    // Backends that register synchronously are done once register returns.
+    fn state(&self, _key: &str) -> RegistrationState {
+        RegistrationState::Registered
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    // Bonjour on Windows, Avahi's compat library on Linux
+    #[default]
+    Dnssd,
+    // The responder in backend::mdns, configured by [mdns]
+    Builtin,
+    // Keeps registrations in process only; nothing reaches the network
+    Memory,
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            BackendKind::Dnssd => "dnssd",
+            BackendKind::Builtin => "builtin",
+            BackendKind::Memory => "memory",
+        })
+    }
+}
+
+pub fn new(kind: BackendKind, mdns: &mdns::MdnsConfig) -> Result<Box<dyn Backend>> {
+    Ok(match kind {
+        BackendKind::Dnssd => Box::<dnssd::DnssdBackend>::default(),
+        BackendKind::Builtin => Box::new(mdns::MdnsBackend::start(mdns).map_err(Error::Responder)?),
+        BackendKind::Memory => Box::<memory::MemoryBackend>::default(),
+    })
+}
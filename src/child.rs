@@ -0,0 +1,138 @@
+// The command an entry's `exec` names, run by windns-sd with the advertised port in
+// PORT so an application that takes any port serves the one being advertised. It is
+// restarted with the [retry] backoff when it exits and stopped along with its entry.
+// It runs in the config file's directory, in the foreground and as a service alike.
+use crate::retry::RetryConfig;
+use std::{
+    path::{Path, PathBuf},
+    process::{self, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+// How long a stopped command gets to exit before it is killed.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+// A command that ran this long had started fine; its restarts count from zero again.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+pub struct Child {
+    key: String,
+    argv: Vec<String>,
+    port: u16,
+    dir: PathBuf,
+    process: Option<process::Child>,
+    started: Instant,
+    // Exits in a row, and when the next start is due while not running
+    restarts: u32,
+    restart_at: Option<Instant>,
+}
+
+impl Child {
+    pub fn start(key: &str, argv: &[String], port: u16, dir: &Path) -> Self {
+        let mut child = Child {
+            key: key.to_string(),
+            argv: argv.to_vec(),
+            port,
+            dir: dir.to_path_buf(),
+            process: None,
+            started: Instant::now(),
+            restarts: 0,
+            restart_at: None,
+        };
+        child.spawn();
+        child
+    }
+
+    pub fn matches(&self, argv: &[String], port: u16) -> bool {
+        self.argv == argv && self.port == port
+    }
+
+    fn spawn(&mut self) {
+        let Some((program, args)) = self.argv.split_first() else {
+            return;
+        };
+        self.started = Instant::now();
+        self.restart_at = None;
+        match Command::new(program)
+            .args(args)
+            .env("PORT", self.port.to_string())
+            .current_dir(&self.dir)
+            .stdin(Stdio::null())
+            .spawn()
+        {
+            Ok(process) => {
+                log::info!(service = self.key.as_str(), pid = process.id(), port = self.port; "Started exec");
+                self.process = Some(process);
+            }
+            Err(e) => {
+                log::error!(service = self.key.as_str(), program = program.as_str(), error:% = e; "Can't start exec");
+                self.restart_at = Some(Instant::now());
+            }
+        }
+    }
+
+    // Notice the command exiting and start it again once its backoff ran out.
+    pub fn poll(&mut self, retry: &RetryConfig) {
+        if let Some(process) = &mut self.process {
+            let Ok(Some(status)) = process.try_wait() else {
+                return;
+            };
+            self.process = None;
+            self.restart_at = Some(Instant::now());
+            log::warn!(service = self.key.as_str(), status:% = status; "exec exited");
+        }
+        let Some(exited_at) = self.restart_at else {
+            return;
+        };
+        if exited_at.duration_since(self.started) >= STABLE_AFTER {
+            self.restarts = 0;
+        }
+        if self.restarts > 0 && exited_at + retry.delay(self.restarts) > Instant::now() {
+            return;
+        }
+        self.restarts += 1;
+        self.spawn();
+    }
+
+    // Ask the command to exit: SIGTERM on Unix, TerminateProcess on Windows.
+    fn terminate(&mut self) {
+        let Some(process) = &mut self.process else {
+            return;
+        };
+        #[cfg(unix)]
+        unsafe {
+            libc::kill(process.id() as libc::pid_t, libc::SIGTERM);
+        }
+        #[cfg(not(unix))]
+        let _ = process.kill();
+    }
+}
+
+impl Drop for Child {
+    fn drop(&mut self) {
+        self.terminate();
+        let Some(mut process) = self.process.take() else {
+            return;
+        };
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        while Instant::now() < deadline {
+            if let Ok(Some(_)) = process.try_wait() {
+                log::info!(service = self.key.as_str(); "Stopped exec");
+                return;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        log::warn!(service = self.key.as_str(); "exec did not exit, killing it");
+        let _ = process.kill();
+        let _ = process.wait();
+    }
+}
+
+// Stop several commands at once rather than waiting for each in turn.
+pub fn stop_all(children: impl IntoIterator<Item = Child>) {
+    let mut children: Vec<Child> = children.into_iter().collect();
+    for child in &mut children {
+        child.terminate();
+    }
+    drop(children);
+}
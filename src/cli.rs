@@ -0,0 +1,433 @@
+use crate::{
+    backend,
+    control::{Request, Response},
+    error::Error,
+    foreground,
+    ipc::{self, Answer},
+    logging,
+    settings::{self, ServiceConfig, Settings},
+    validate::{self, Diagnostic, Severity},
+};
+use clap::{Parser, Subcommand};
+use serde::Serialize;
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "windns-sd",
+    version,
+    about = "Advertise DNS-SD services listed in a TOML file"
+)]
+pub struct Cli {
+    /// Config file to use instead of the default location
+    #[arg(long, short, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    /// Run in the console, same as `run`
+    #[arg(long)]
+    pub foreground: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Advertise the configured services until interrupted
+    Run,
+    /// Load and validate the config file without advertising anything.
+    /// Exits 0 when clean, 1 on errors and 2 when there are only warnings.
+    Check {
+        /// Print the result as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the config as it was parsed
+    PrintConfig,
+    /// List the configured services
+    List,
+    /// Show instances of a service type found on the network
+    Browse {
+        /// Service type, e.g. _smb._tcp
+        service_type: String,
+        /// How long to listen, in seconds
+        #[arg(long, default_value_t = 3)]
+        timeout: u64,
+    },
+    /// Show the running instance's services and their state
+    Status {
+        /// Print the result as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Make the running instance re-read its config file
+    Reload,
+    /// Advertise a service from the running instance until it stops.
+    /// The service is not written to the config file.
+    Add {
+        /// Key to file the service under, like a [services.<key>] table
+        key: String,
+        /// Instance name
+        #[arg(long)]
+        name: String,
+        /// Service type, e.g. _smb._tcp
+        #[arg(long = "type", value_name = "TYPE")]
+        service_type: String,
+        /// Port, 0 picks a free one
+        #[arg(long, default_value_t = 0)]
+        port: u16,
+        /// TXT record entry, may be repeated
+        #[arg(long = "text", value_name = "KEY=VALUE", value_parser = parse_txt_entry)]
+        text: Vec<(String, String)>,
+    },
+    /// Stop advertising a service of the running instance
+    Remove {
+        /// Key of the service
+        key: String,
+    },
+    /// Advertise every service of a group again, including those with enabled = false.
+    /// Lasts until the running instance restarts.
+    Enable {
+        /// Group, as set by `group` in [services.*] entries
+        group: String,
+    },
+    /// Stop advertising every service of a group until it is enabled again or the
+    /// running instance restarts
+    Disable {
+        /// Group, as set by `group` in [services.*] entries
+        group: String,
+    },
+}
+
+fn parse_txt_entry(entry: &str) -> Result<(String, String), String> {
+    entry
+        .split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got \"{}\"", entry))
+}
+
+impl Cli {
+    pub fn config_path(&self) -> PathBuf {
+        self.config
+            .clone()
+            .unwrap_or_else(settings::default_config_path)
+    }
+}
+
+pub fn run(cli: Cli) -> ExitCode {
+    let config_path = cli.config_path();
+    match cli.command {
+        Some(Command::Run) => run_foreground(config_path),
+        None if cli.foreground => run_foreground(config_path),
+        #[cfg(windows)]
+        None => start_service(cli.config),
+        #[cfg(not(windows))]
+        None => run_foreground(config_path),
+        Some(Command::Check { json }) => check(&config_path, json),
+        Some(Command::PrintConfig) => {
+            let Some(config) = load(&config_path) else {
+                return ExitCode::FAILURE;
+            };
+            match toml::to_string_pretty(&config) {
+                Ok(text) => {
+                    print!("{}", text);
+                    ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    eprintln!("Error printing config: {}", e);
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        Some(Command::Browse {
+            service_type,
+            timeout,
+        }) => browse(&config_path, &service_type, Duration::from_secs(timeout)),
+        Some(Command::List) => {
+            let Some(config) = load(&config_path) else {
+                return ExitCode::FAILURE;
+            };
+            // Files are shown relative to the config file's directory
+            let dir = settings::config_dir(&config_path);
+            println!(
+                "{:<20} {:<24} {:<20} {:>5}  {:<20} FILE",
+                "KEY", "TYPE", "NAME", "PORT", "GROUP"
+            );
+            for (key, service_config) in &config.services {
+                let group = format!(
+                    "{}{}",
+                    service_config.group.as_deref().unwrap_or("-"),
+                    if service_config.enabled == Some(false) {
+                        " (disabled)"
+                    } else {
+                        ""
+                    }
+                );
+                let file = config.files.get(key).map_or_else(String::new, |file| {
+                    file.strip_prefix(dir).unwrap_or(file).display().to_string()
+                });
+                println!(
+                    "{:<20} {:<24} {:<20} {:>5}  {:<20} {}",
+                    key,
+                    service_config.service_type,
+                    service_config.name,
+                    service_config.port,
+                    group,
+                    file
+                );
+            }
+            ExitCode::SUCCESS
+        }
+        Some(Command::Status { json }) => {
+            let Some(Response::Services(services)) = control(&config_path, Request::List) else {
+                return ExitCode::FAILURE;
+            };
+            if json {
+                match serde_json::to_string_pretty(&services) {
+                    Ok(text) => println!("{}", text),
+                    Err(e) => {
+                        eprintln!("Error printing status: {}", e);
+                        return ExitCode::FAILURE;
+                    }
+                }
+                return ExitCode::SUCCESS;
+            }
+            println!(
+                "{:<20} {:<11} {:<24} {:<20} {:>5}",
+                "KEY", "STATE", "TYPE", "NAME", "PORT"
+            );
+            for service in &services {
+                println!(
+                    "{:<20} {:<11} {:<24} {:<20} {:>5}",
+                    service.key, service.state, service.service_type, service.name, service.port
+                );
+                match &service.error {
+                    Some(error) if service.failures > 0 => {
+                        println!("    {} (attempt {})", error, service.failures)
+                    }
+                    Some(error) => println!("    {}", error),
+                    None => {}
+                }
+            }
+            ExitCode::SUCCESS
+        }
+        Some(Command::Reload) => match control(&config_path, Request::Reload) {
+            Some(Response::Services(services)) => {
+                println!("Reloaded, {} services", services.len());
+                ExitCode::SUCCESS
+            }
+            _ => ExitCode::FAILURE,
+        },
+        Some(Command::Add {
+            key,
+            name,
+            service_type,
+            port,
+            text,
+        }) => {
+            let config = Box::new(ServiceConfig {
+                name,
+                service_type,
+                port,
+                text: (!text.is_empty()).then(|| text.into_iter().collect()),
+                ..Default::default()
+            });
+            match control(&config_path, Request::Add { key, config }) {
+                Some(Response::Service(service)) => {
+                    println!(
+                        "Added {} ({} on port {}), {}",
+                        service.key, service.service_type, service.port, service.state
+                    );
+                    ExitCode::SUCCESS
+                }
+                _ => ExitCode::FAILURE,
+            }
+        }
+        Some(Command::Enable { group }) => set_group(&config_path, group, true),
+        Some(Command::Disable { group }) => set_group(&config_path, group, false),
+        Some(Command::Remove { key }) => {
+            match control(&config_path, Request::Remove { key: key.clone() }) {
+                Some(Response::Removed) => {
+                    println!("Removed {}", key);
+                    ExitCode::SUCCESS
+                }
+                _ => ExitCode::FAILURE,
+            }
+        }
+    }
+}
+
+// Send `request` to the running instance over its control channel, printing why it
+// didn't work out if it didn't.
+fn control(config_path: &Path, request: Request) -> Option<Response> {
+    let endpoint = Settings::from_file(config_path)
+        .map(|config| config.ipc)
+        .unwrap_or_default()
+        .endpoint(config_path);
+    match ipc::request(&endpoint, &request) {
+        Ok(Answer::Ok(response)) => Some(response),
+        Ok(Answer::Error {
+            message,
+            diagnostics,
+        }) => {
+            if diagnostics.is_empty() {
+                eprintln!("Error: {}", message);
+            }
+            for diagnostic in diagnostics {
+                eprintln!("{}", diagnostic);
+            }
+            None
+        }
+        Err(e) => {
+            eprintln!(
+                "Can't reach windns-sd at {}, is it running? {}",
+                endpoint.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+fn set_group(config_path: &Path, group: String, enabled: bool) -> ExitCode {
+    let request = Request::SetGroup {
+        group: group.clone(),
+        enabled,
+    };
+    let Some(Response::Services(services)) = control(config_path, request) else {
+        return ExitCode::FAILURE;
+    };
+    println!(
+        "{} group {}: {}",
+        if enabled { "Enabled" } else { "Disabled" },
+        group,
+        services
+            .iter()
+            .map(|service| format!("{} {}", service.key, service.state))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    ExitCode::SUCCESS
+}
+
+#[derive(Serialize)]
+struct CheckReport<'a> {
+    path: &'a Path,
+    services: usize,
+    errors: usize,
+    warnings: usize,
+    diagnostics: Vec<Diagnostic>,
+}
+
+fn check(config_path: &Path, json: bool) -> ExitCode {
+    let (services, diagnostics) = match Settings::from_file(config_path) {
+        Ok(config) => (config.services.len(), validate::validate(&config)),
+        Err(Error::Invalid { diagnostics, .. }) => (0, diagnostics),
+        // A file that doesn't parse is reported like any other error
+        Err(e) => (0, vec![Diagnostic::error(String::new(), e.to_string())]),
+    };
+    let count = |severity| {
+        diagnostics
+            .iter()
+            .filter(|d| d.severity == severity)
+            .count()
+    };
+    let report = CheckReport {
+        path: config_path,
+        services,
+        errors: count(Severity::Error),
+        warnings: count(Severity::Warning),
+        diagnostics,
+    };
+    if json {
+        match serde_json::to_string_pretty(&report) {
+            Ok(text) => println!("{}", text),
+            Err(e) => {
+                eprintln!("Error printing report: {}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        for diagnostic in &report.diagnostics {
+            println!("{}", diagnostic);
+        }
+        println!(
+            "{}: {} services, {} errors, {} warnings",
+            config_path.display(),
+            report.services,
+            report.errors,
+            report.warnings
+        );
+    }
+    if report.errors > 0 {
+        ExitCode::from(1)
+    } else if report.warnings > 0 {
+        ExitCode::from(2)
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+fn browse(config_path: &Path, service_type: &str, timeout: Duration) -> ExitCode {
+    // The builtin responder reports socket errors through the log
+    logging::init_console();
+    // Browse through the configured backend; without a config file, the default one
+    let (kind, mdns) = Settings::from_file(config_path)
+        .map(|config| (config.backend, config.mdns))
+        .unwrap_or_default();
+    let found = backend::new(kind, &mdns).and_then(|mut b| b.browse(service_type, timeout));
+    match found {
+        Ok(found) => {
+            for d in found {
+                let mut text: Vec<_> = d.text.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+                text.sort();
+                println!(
+                    "{}\t{}\t{}:{}\t{}",
+                    d.name,
+                    d.service_type,
+                    d.hostname,
+                    d.port,
+                    text.join(" ")
+                );
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from(e.exit_code() as u8)
+        }
+    }
+}
+
+fn load(config_path: &Path) -> Option<Settings> {
+    match Settings::from_file(config_path) {
+        Ok(config) => Some(config),
+        Err(e) => {
+            eprintln!("{}", e);
+            None
+        }
+    }
+}
+
+fn run_foreground(config_path: PathBuf) -> ExitCode {
+    match foreground::run(&config_path) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            log::error!("{}", e);
+            ExitCode::from(e.exit_code() as u8)
+        }
+    }
+}
+
+#[cfg(windows)]
+fn start_service(config_path: Option<PathBuf>) -> ExitCode {
+    match crate::service::start(config_path) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error starting service dispatcher: {:?}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
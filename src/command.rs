@@ -0,0 +1,107 @@
+// Running the shell commands a config file can name.
+use std::{
+    io::{self, Read},
+    process::{Command, Output, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+// `sh -c command`, or `cmd /C command` on Windows.
+pub fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C").arg(command);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c").arg(command);
+        shell
+    }
+}
+
+// Run `command` to completion and collect its output, killing it once `timeout` ran out.
+// Output still coming after that, from something the command left running in the
+// background with the pipes open, is cut off.
+pub fn output(command: &str, timeout: Duration) -> io::Result<Output> {
+    let mut child = shell(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Drain both pipes while waiting, a chatty command would block on a full one
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("still running after {}s", timeout.as_secs()),
+            ));
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    Ok(Output {
+        status,
+        stdout: collect(&stdout, deadline),
+        stderr: collect(&stderr, deadline),
+    })
+}
+
+// Why a command that ran to completion failed: its exit status and what it printed to
+// stderr.
+pub fn failure(output: &Output) -> String {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if stderr.trim().is_empty() {
+        output.status.to_string()
+    } else {
+        format!("{}: {}", output.status, stderr.trim())
+    }
+}
+
+// Read a pipe on a thread of its own, handing over what arrives.
+fn drain(pipe: Option<impl Read + Send + 'static>) -> mpsc::Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let Some(mut pipe) = pipe else {
+            return;
+        };
+        let mut buffer = [0; 4096];
+        while let Ok(read @ 1..) = pipe.read(&mut buffer) {
+            if sender.send(buffer[..read].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+// What a drain thread read until its pipe closed or `deadline` passed. The thread is
+// left behind in the latter case; it ends once the pipe closes.
+fn collect(chunks: &mpsc::Receiver<Vec<u8>>, deadline: Instant) -> Vec<u8> {
+    let mut output = Vec::new();
+    while let Ok(chunk) = chunks.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        output.extend(chunk);
+    }
+    output
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn background_process_holding_the_pipes_is_cut_off() {
+        let started = Instant::now();
+        let output = output("echo done; sleep 30 &", Duration::from_secs(1)).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"done\n");
+    }
+}
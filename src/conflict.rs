@@ -0,0 +1,67 @@
+// What to do when another host already advertises an entry's name (RFC 6762 §9):
+// pick a new one and register again, or give up and stay Conflicted.
+use crate::validate::MAX_LABEL_LEN;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictStrategy {
+    // "MacPro (2)", then "MacPro (3)", ...
+    #[default]
+    Number,
+    // "MacPro (<hostname>)", then "MacPro (<hostname> 2)", ...
+    Hostname,
+    Fail,
+}
+
+// The name to try after `current` conflicted, None if the strategy is to give up.
+// `current` is `configured` or an earlier pick of the same strategy.
+pub fn next_name(
+    configured: &str,
+    current: &str,
+    strategy: ConflictStrategy,
+    hostname: &str,
+) -> Option<String> {
+    let tag = match strategy {
+        ConflictStrategy::Number => None,
+        ConflictStrategy::Hostname => Some(hostname),
+        ConflictStrategy::Fail => return None,
+    };
+    let attempt = attempt(configured, current, tag) + 1;
+    let suffix = match (tag, attempt) {
+        (None, n) => format!(" ({})", n + 1),
+        (Some(tag), 1) => format!(" ({})", tag),
+        (Some(tag), n) => format!(" ({} {})", tag, n),
+    };
+    // The suffix must still fit in one DNS label, so long names lose their end instead
+    let mut end = MAX_LABEL_LEN
+        .saturating_sub(suffix.len())
+        .min(configured.len());
+    while !configured.is_char_boundary(end) {
+        end -= 1;
+    }
+    Some(format!("{}{}", &configured[..end], suffix))
+}
+
+// How many renames `current` is away from `configured`: 0 for the configured name
+// itself, or for anything next_name wouldn't have picked.
+fn attempt(configured: &str, current: &str, tag: Option<&str>) -> u32 {
+    let Some((base, inner)) = current
+        .strip_suffix(')')
+        .and_then(|rest| rest.rsplit_once(" ("))
+    else {
+        return 0;
+    };
+    if current == configured || !configured.starts_with(base) {
+        return 0;
+    }
+    match tag {
+        None => inner.parse::<u32>().map_or(0, |n| n.saturating_sub(1)),
+        Some(tag) if inner == tag => 1,
+        Some(tag) => inner
+            .strip_prefix(tag)
+            .and_then(|n| n.strip_prefix(' '))
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(0),
+    }
+}
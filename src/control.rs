@@ -0,0 +1,117 @@
+use crate::{
+    backend::RegistrationState,
+    daemon::Event,
+    settings::ServiceConfig,
+    validate::{Diagnostic, Severity},
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, path::PathBuf, sync::mpsc, time::Duration};
+
+// How long a control request waits for the worker loop before giving up.
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// What the HTTP API or the IPC channel asks the running Advertiser to do. Serialized
+// as `{"command": "add", "key": ..., "config": {...}}` on the IPC channel.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    List,
+    Get {
+        key: String,
+    },
+    // A transient advertisement: not in config.toml, kept across reloads
+    Add {
+        key: String,
+        config: Box<ServiceConfig>,
+    },
+    Remove {
+        key: String,
+    },
+    UpdateTxt {
+        key: String,
+        text: HashMap<String, String>,
+    },
+    Reload,
+    // Switch every entry of a group off or on, until the daemon restarts
+    SetGroup {
+        group: String,
+        enabled: bool,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Services(Vec<ServiceStatus>),
+    Service(ServiceStatus),
+    Removed,
+}
+
+#[derive(Debug)]
+pub enum ControlError {
+    NotFound(String),
+    Exists(String),
+    NoGroup(String),
+    Invalid(Vec<Diagnostic>),
+    // A reload that didn't go through
+    Failed(String),
+    // The worker loop didn't answer within REPLY_TIMEOUT or is gone
+    Unavailable,
+}
+
+impl fmt::Display for ControlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlError::NotFound(key) => write!(f, "no service {}", key),
+            ControlError::Exists(key) => write!(f, "service {} already exists", key),
+            ControlError::NoGroup(group) => write!(f, "no services in group {}", group),
+            ControlError::Invalid(diagnostics) => {
+                let errors: Vec<String> = diagnostics
+                    .iter()
+                    .filter(|d| d.severity == Severity::Error)
+                    .map(|d| format!("{}: {}", d.key, d.message))
+                    .collect();
+                write!(f, "invalid service: {}", errors.join("; "))
+            }
+            ControlError::Failed(message) => f.write_str(message),
+            ControlError::Unavailable => write!(f, "the daemon is not responding"),
+        }
+    }
+}
+
+pub type Reply = Result<Response, ControlError>;
+
+// One registration as reported by `GET /services` and `windns-sd status`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceStatus {
+    pub key: String,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub service_type: String,
+    pub port: u16,
+    pub text: HashMap<String, String>,
+    pub state: RegistrationState,
+    pub transient: bool,
+    // The config file or drop-in the entry is defined in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub failures: u32,
+}
+
+fn is_zero(failures: &u32) -> bool {
+    *failures == 0
+}
+
+// Hand `request` to the worker loop through `events` and wait for its answer.
+pub fn send(events: &mpsc::Sender<Event>, request: Request) -> Reply {
+    let (reply_tx, reply_rx) = mpsc::channel();
+    events
+        .send(Event::Control(request, reply_tx))
+        .map_err(|_| ControlError::Unavailable)?;
+    reply_rx
+        .recv_timeout(REPLY_TIMEOUT)
+        .map_err(|_| ControlError::Unavailable)?
+}
@@ -0,0 +1,1455 @@
+use crate::{
+    advertise,
+    api::{Api, ApiConfig},
+    backend::{self, mdns::MdnsConfig, Advertisement, Backend, BackendKind, RegistrationState},
+    child::{self, Child},
+    conflict,
+    control::{ControlError, Reply, Request, Response, ServiceStatus},
+    error::{Error, Result},
+    health::Health,
+    host,
+    ipc::{Ipc, IpcConfig},
+    metrics::{self, Exporter, MetricsConfig},
+    process::ProcessWatch,
+    retry::RetryConfig,
+    settings::{self, ServiceConfig, Settings},
+    state::{self, Renamed, State},
+    text::{DynamicText, Text},
+    validate::{self, Diagnostic},
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+// How long Stop waits for the backend to withdraw everything before giving up.
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+// Upper bound between two progress callbacks during shutdown.
+pub const SHUTDOWN_PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+// How often run() checks retries, backend states, texts, health checks, processes,
+// exec commands and the config file, however busy the event channel is.
+const TICK: Duration = Duration::from_secs(1);
+
+// What the SCM handler, the signal thread or the API asks the worker loop to do.
+#[derive(Debug)]
+pub enum Event {
+    Stop,
+    Reload,
+    Control(Request, mpsc::Sender<Reply>),
+    // A text_command finished, with its parsed output or why it failed
+    Text(String, std::result::Result<Text, String>),
+    // A health check finished
+    Health(String, std::result::Result<(), String>),
+}
+
+// One [services.*] entry and how far its registration got.
+struct Registration {
+    config: ServiceConfig,
+    // 0 until a port = 0 entry had a port allocated
+    port: u16,
+    state: RegistrationState,
+    error: Option<String>,
+    // Failures in a row and when the next attempt is due, while Failed
+    failures: u32,
+    retry_at: Option<Instant>,
+    // Added through the API rather than config.toml; reloads leave it alone
+    transient: bool,
+}
+
+// Owns every registration, keyed by their [services.*] table name, and keeps their
+// state in sync with the backend.
+pub struct Advertiser {
+    config_path: PathBuf,
+    // Modification times of the config file and its drop-ins, to notice edits
+    modified: Vec<(PathBuf, Option<SystemTime>)>,
+    backend_kind: BackendKind,
+    mdns: MdnsConfig,
+    retry: RetryConfig,
+    api_config: ApiConfig,
+    api: Option<Api>,
+    ipc_config: IpcConfig,
+    ipc: Option<Ipc>,
+    metrics_config: MetricsConfig,
+    exporter: Option<Exporter>,
+    backend: Box<dyn Backend>,
+    registrations: BTreeMap<String, Registration>,
+    // text_file and text_command values, for the entries that have either
+    texts: BTreeMap<String, DynamicText>,
+    // Health checks, for the entries that have one
+    healths: BTreeMap<String, Health>,
+    // Process watches, for the entries that have process or pid_file
+    processes: BTreeMap<String, ProcessWatch>,
+    // The running exec commands, for the entries that have one
+    children: BTreeMap<String, Child>,
+    // Groups switched off or on at runtime; they override `enabled` until a restart
+    groups: BTreeMap<String, bool>,
+    // The file each configured entry came from
+    files: BTreeMap<String, PathBuf>,
+    // Ports remembered for port = 0 entries and names picked after a conflict, and the
+    // config's fixed ports the ports must stay clear of
+    state_path: PathBuf,
+    state: State,
+    fixed_ports: BTreeSet<u16>,
+    events: mpsc::Sender<Event>,
+}
+
+impl Advertiser {
+    // `events` is the sender side of the channel run() reads; the API and the IPC
+    // channel post to it.
+    pub fn start(config_path: &Path, events: &mpsc::Sender<Event>) -> Result<Self> {
+        let settings = Settings::load(config_path)?;
+        let backend = backend::new(settings.backend, &settings.mdns)?;
+        Self::with_backend(config_path, settings, backend, events)
+    }
+
+    // start with a backend that's already there.
+    fn with_backend(
+        config_path: &Path,
+        settings: Settings,
+        backend: Box<dyn Backend>,
+        events: &mpsc::Sender<Event>,
+    ) -> Result<Self> {
+        let api = if settings.api.enabled {
+            Some(Api::start(&settings.api, events.clone()).map_err(Error::Api)?)
+        } else {
+            None
+        };
+        let ipc = if settings.ipc.enabled {
+            let endpoint = settings.ipc.endpoint(config_path);
+            Some(Ipc::start(&endpoint, events.clone()).map_err(Error::Ipc)?)
+        } else {
+            None
+        };
+        let exporter = if settings.metrics.enabled {
+            Some(Exporter::start(&settings.metrics, events.clone()).map_err(Error::Metrics)?)
+        } else {
+            None
+        };
+        let state_path = state::path(settings.state_file.as_deref(), config_path);
+        let mut advertiser = Advertiser {
+            state: State::load(&state_path),
+            state_path,
+            fixed_ports: BTreeSet::new(),
+            config_path: config_path.to_path_buf(),
+            modified: modified(config_path),
+            backend_kind: settings.backend,
+            mdns: settings.mdns.clone(),
+            retry: settings.retry.clone(),
+            api_config: settings.api.clone(),
+            api,
+            ipc_config: settings.ipc.clone(),
+            ipc,
+            metrics_config: settings.metrics.clone(),
+            exporter,
+            backend,
+            registrations: BTreeMap::new(),
+            texts: BTreeMap::new(),
+            healths: BTreeMap::new(),
+            processes: BTreeMap::new(),
+            children: BTreeMap::new(),
+            groups: BTreeMap::new(),
+            files: BTreeMap::new(),
+            events: events.clone(),
+        };
+        advertiser.apply(settings);
+        Ok(advertiser)
+    }
+
+    // Re-read the config file and bring the running set in line with it. An invalid
+    // file leaves everything as it was.
+    pub fn reload(&mut self) -> Result<()> {
+        self.modified = modified(&self.config_path);
+        let settings = Settings::load(&self.config_path).inspect_err(|_| metrics::reload(false))?;
+        if settings.backend != self.backend_kind || settings.mdns != self.mdns {
+            log::warn!("Changing the backend takes effect after a restart");
+        }
+        if settings.api != self.api_config {
+            log::warn!("Changing [api] takes effect after a restart");
+        }
+        if settings.ipc != self.ipc_config {
+            log::warn!("Changing [ipc] takes effect after a restart");
+        }
+        if settings.metrics != self.metrics_config {
+            log::warn!("Changing [metrics] takes effect after a restart");
+        }
+        if state::path(settings.state_file.as_deref(), &self.config_path) != self.state_path {
+            log::warn!("Changing state_file takes effect after a restart");
+        }
+        self.apply(settings);
+        metrics::reload(true);
+        Ok(())
+    }
+
+    // Reload when a modification time moved or a drop-in came or went.
+    pub fn reload_if_changed(&mut self) -> Result<()> {
+        if modified(&self.config_path) == self.modified {
+            return Ok(());
+        }
+        log::info!(path:% = self.config_path.display(); "Config changed, reloading");
+        self.reload()
+    }
+
+    // Each registration's key and state.
+    pub fn states(&self) -> impl Iterator<Item = (&str, RegistrationState)> {
+        self.registrations
+            .iter()
+            .map(|(key, registration)| (key.as_str(), registration.state))
+    }
+
+    fn apply(&mut self, settings: Settings) {
+        self.retry = settings.retry;
+        self.files = settings.files;
+        let services = settings.services;
+        self.fixed_ports = services
+            .values()
+            .map(|c| c.port)
+            .filter(|p| *p != 0)
+            .collect();
+        // Forget the ports of entries that are gone or got a fixed port, and the names of
+        // entries that are gone or got another name
+        let remembered = (self.state.ports.len(), self.state.names.len());
+        self.state
+            .ports
+            .retain(|key, _| services.get(key).is_some_and(|c| c.port == 0));
+        let registrations = &self.registrations;
+        self.state
+            .names
+            .retain(|key, renamed| match services.get(key) {
+                Some(c) => c.name == renamed.configured,
+                None => registrations.get(key).is_some_and(|r| r.transient),
+            });
+        if (self.state.ports.len(), self.state.names.len()) != remembered {
+            self.save_state();
+        }
+        let removed: Vec<String> = self
+            .registrations
+            .iter()
+            .filter(|(key, registration)| !registration.transient && !services.contains_key(*key))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in removed {
+            self.backend.unregister(&key);
+            self.registrations.remove(&key);
+            self.unwatch(&key);
+            log::info!(service = key.as_str(); "Unregistered");
+        }
+        for (key, service_config) in services {
+            let previous_port = match self.registrations.get_mut(&key) {
+                // The config file takes over a key that was added through the API
+                Some(registration) if registration.transient => None,
+                // Failed entries are retried right away on every reload, without
+                // resetting the backoff unless the entry changed
+                Some(registration) if registration.state == RegistrationState::Failed => {
+                    let failures = if registration.config == service_config {
+                        registration.failures
+                    } else {
+                        0
+                    };
+                    self.register(key, service_config, None, failures, false);
+                    continue;
+                }
+                Some(registration) if registration.config == service_config => continue,
+                // Only the TXT record changed: update it in place
+                Some(registration) if same_except_text(&registration.config, &service_config) => {
+                    registration.config = service_config.clone();
+                    if registration.state == RegistrationState::Disabled {
+                        continue;
+                    }
+                    self.watch_text(&key, &service_config);
+                    self.push_text(&key);
+                    continue;
+                }
+                // Keep the port picked for port = 0 across re-registrations
+                Some(registration)
+                    if registration.config.port == service_config.port
+                        && registration.config.port_range == service_config.port_range =>
+                {
+                    Some(registration.port)
+                }
+                _ => None,
+            };
+            self.register(
+                key,
+                service_config,
+                previous_port.filter(|port| *port != 0),
+                0,
+                false,
+            );
+        }
+        self.summary();
+    }
+
+    // A failed registration is kept in state Failed, with a retry scheduled according to
+    // [retry], rather than failing the whole set. `failures` counts earlier attempts.
+    // A switched off entry is only recorded, as Disabled. Otherwise the port is handed
+    // to port_file and exec first, so a health check can find the application on it; an
+    // entry then held back by its process or health check is only recorded, as Inactive
+    // or Unhealthy.
+    fn register(
+        &mut self,
+        key: String,
+        service_config: ServiceConfig,
+        port: Option<u16>,
+        failures: u32,
+        transient: bool,
+    ) {
+        if let Some(error) = self.disabled(&service_config) {
+            if let Some(previous) = self.registrations.get(&key) {
+                if previous.state.in_backend() {
+                    self.backend.unregister(&key);
+                    log::info!(service = key.as_str(), state:% = RegistrationState::Disabled; "Unregistered");
+                }
+            }
+            self.unwatch(&key);
+            self.registrations.insert(
+                key,
+                Registration {
+                    port: port.unwrap_or(service_config.port),
+                    config: service_config,
+                    state: RegistrationState::Disabled,
+                    error: Some(error),
+                    failures: 0,
+                    retry_at: None,
+                    transient,
+                },
+            );
+            return;
+        }
+        self.watch_text(&key, &service_config);
+        self.watch_health(&key, &service_config);
+        self.watch_process(&key, &service_config);
+        let resolved = match port {
+            Some(port) => Ok(port),
+            None => self.allocate_port(&key, &service_config, transient),
+        };
+        if let Ok(port) = resolved {
+            self.export(&key, &service_config, port);
+            if let Some((state, error)) = self.gate(&key) {
+                if let Some(previous) = self.registrations.get(&key) {
+                    if previous.state.in_backend() {
+                        self.backend.unregister(&key);
+                    }
+                }
+                self.registrations.insert(
+                    key,
+                    Registration {
+                        config: service_config,
+                        port,
+                        state,
+                        error: Some(error),
+                        failures: 0,
+                        retry_at: None,
+                        transient,
+                    },
+                );
+                return;
+            }
+        }
+        let text = self.text_of(&key, &service_config);
+        let name = self.name_of(&key, &service_config);
+        let result = resolved.and_then(|port| {
+            let advertisement = Advertisement {
+                name,
+                text,
+                ..Advertisement::new(&service_config, port)
+            };
+            self.backend.register(&key, &advertisement).map(|()| port)
+        });
+        metrics::registration_attempt(&key, &service_config.service_type, result.is_ok());
+        let registration = match result {
+            Ok(port) => {
+                // The builtin responder is still probing; refresh logs when it's done
+                let state = self.backend.state(&key);
+                log::info!(
+                    service = key.as_str(),
+                    type = service_config.service_type.as_str(),
+                    port,
+                    backend:% = self.backend_kind;
+                    "{}",
+                    if state == RegistrationState::Registered {
+                        "Registered"
+                    } else {
+                        "Registering"
+                    }
+                );
+                Registration {
+                    config: service_config,
+                    port,
+                    state,
+                    error: None,
+                    failures: 0,
+                    retry_at: None,
+                    transient,
+                }
+            }
+            Err(e) => {
+                let failures = failures + 1;
+                let delay = self.retry.delay(failures);
+                log::error!(
+                    service = key.as_str(),
+                    type = service_config.service_type.as_str(),
+                    backend:% = self.backend_kind,
+                    attempt = failures,
+                    retry_in:% = format_args!("{:.1}s", delay.as_secs_f64()),
+                    error:% = e;
+                    "Registration failed"
+                );
+                Registration {
+                    config: service_config,
+                    port: port.unwrap_or(0),
+                    state: RegistrationState::Failed,
+                    error: Some(e.to_string()),
+                    failures,
+                    retry_at: Some(Instant::now() + delay),
+                    transient,
+                }
+            }
+        };
+        self.registrations.insert(key, registration);
+    }
+
+    // Resolve port = 0, remembering the port in the state file for the next start.
+    // Entries added through the API get a port but aren't remembered.
+    fn allocate_port(
+        &mut self,
+        key: &str,
+        service_config: &ServiceConfig,
+        transient: bool,
+    ) -> Result<u16> {
+        let taken: BTreeSet<u16> = self
+            .registrations
+            .iter()
+            .filter(|(other, _)| *other != key)
+            .map(|(_, registration)| registration.port)
+            .chain(
+                self.state
+                    .ports
+                    .iter()
+                    .filter(|(other, _)| *other != key)
+                    .map(|(_, port)| *port),
+            )
+            .chain(self.fixed_ports.iter().copied())
+            .filter(|port| *port != 0)
+            .collect();
+        let remembered = self.state.ports.get(key).copied();
+        let port = advertise::resolve_port(key, service_config, remembered, &taken)?;
+        if service_config.port == 0 && !transient && remembered != Some(port) {
+            if let Some(remembered) = remembered {
+                log::info!(service = key, remembered, port; "Remembered port is no longer usable");
+            }
+            self.state.ports.insert(key.to_string(), port);
+            self.save_state();
+        }
+        Ok(port)
+    }
+
+    // Hand the port to the application: rewrite port_file if it holds something else,
+    // and (re)start exec if it isn't running with this port yet.
+    fn export(&mut self, key: &str, service_config: &ServiceConfig, port: u16) {
+        if let Some(path) = &service_config.port_file {
+            let contents = format!("{}\n", port);
+            if fs::read_to_string(path).ok().as_deref() != Some(contents.as_str()) {
+                if let Err(e) = fs::write(path, contents) {
+                    log::error!(service = key, path:% = path.display(), error:% = e; "Can't write port_file");
+                }
+            }
+        }
+        match &service_config.exec {
+            Some(argv)
+                if self
+                    .children
+                    .get(key)
+                    .is_some_and(|c| c.matches(argv, port)) => {}
+            Some(argv) => {
+                // The old command has to let go of the port before the new one starts
+                self.children.remove(key);
+                let dir = settings::config_dir(&self.config_path);
+                self.children
+                    .insert(key.to_string(), Child::start(key, argv, port, dir));
+            }
+            None => {
+                self.children.remove(key);
+            }
+        }
+    }
+
+    // Restart the exec commands that exited, once their backoff ran out.
+    fn poll_children(&mut self) {
+        for child in self.children.values_mut() {
+            child.poll(&self.retry);
+        }
+    }
+
+    // Why an entry is switched off, if it is. A group switched at runtime beats `enabled`.
+    fn disabled(&self, service_config: &ServiceConfig) -> Option<String> {
+        let group = service_config.group.as_ref();
+        match group.and_then(|group| self.groups.get(group)) {
+            Some(true) => None,
+            Some(false) => Some(format!(
+                "group {} is disabled",
+                group.map(String::as_str).unwrap_or_default()
+            )),
+            None if service_config.enabled == Some(false) => Some("enabled = false".into()),
+            None => None,
+        }
+    }
+
+    // Register or withdraw an entry after its group was switched.
+    fn switch(&mut self, key: &str) {
+        let Some(registration) = self.registrations.get(key) else {
+            return;
+        };
+        let disabled = self.disabled(&registration.config).is_some();
+        if disabled == (registration.state == RegistrationState::Disabled) {
+            return;
+        }
+        let port = Some(registration.port).filter(|port| *port != 0);
+        let (config, transient) = (registration.config.clone(), registration.transient);
+        self.register(key.to_string(), config, port, 0, transient);
+    }
+
+    // The configured name, or the one picked after it conflicted.
+    fn name_of(&self, key: &str, service_config: &ServiceConfig) -> String {
+        match self.state.names.get(key) {
+            Some(renamed) if renamed.configured == service_config.name => renamed.name.clone(),
+            _ => service_config.name.clone(),
+        }
+    }
+
+    // Register a conflicted entry again under the name its on_conflict strategy picks
+    // next, and remember that name. With on_conflict = "fail" it stays Conflicted.
+    fn rename(&mut self, key: &str) {
+        let Some(registration) = self.registrations.get(key) else {
+            return;
+        };
+        let (config, port, transient) = (
+            registration.config.clone(),
+            registration.port,
+            registration.transient,
+        );
+        let current = self.name_of(key, &config);
+        // The host name the builtin responder advertises
+        let hostname = self.mdns.hostname.clone().unwrap_or_else(host::hostname);
+        let Some(name) = conflict::next_name(&config.name, &current, config.on_conflict, &hostname)
+        else {
+            return;
+        };
+        log::warn!(
+            service = key,
+            type = config.service_type.as_str(),
+            name = current.as_str(),
+            renamed = name.as_str();
+            "Renamed after a name conflict"
+        );
+        metrics::rename(key, &config.service_type);
+        self.state.names.insert(
+            key.to_string(),
+            Renamed {
+                configured: config.name.clone(),
+                name,
+            },
+        );
+        self.save_state();
+        let port = Some(port).filter(|port| *port != 0);
+        self.register(key.to_string(), config, port, 0, transient);
+    }
+
+    fn save_state(&self) {
+        if let Err(e) = self.state.save(&self.state_path) {
+            log::warn!(path:% = self.state_path.display(), error:% = e; "Can't write state file");
+        }
+    }
+
+    // Try again every failed registration whose backoff ran out.
+    fn retry_failed(&mut self) {
+        let now = Instant::now();
+        let due: Vec<String> = self
+            .registrations
+            .iter()
+            .filter(|(_, registration)| registration.retry_at.is_some_and(|at| at <= now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in due {
+            if let Some(registration) = self.registrations.remove(&key) {
+                let port = Some(registration.port).filter(|port| *port != 0);
+                self.register(
+                    key,
+                    registration.config,
+                    port,
+                    registration.failures,
+                    registration.transient,
+                );
+            }
+        }
+    }
+
+    // Start, restart or stop following an entry's text_file and text_command to match
+    // its config.
+    fn watch_text(&mut self, key: &str, service_config: &ServiceConfig) {
+        if self
+            .texts
+            .get(key)
+            .is_some_and(|dynamic| dynamic.matches(service_config))
+        {
+            return;
+        }
+        match DynamicText::new(key, service_config) {
+            Some(dynamic) => {
+                self.texts.insert(key.to_string(), dynamic);
+            }
+            None => {
+                self.texts.remove(key);
+            }
+        }
+    }
+
+    // The TXT record as advertised: `text` plus the text_file and text_command values.
+    fn text_of(&self, key: &str, service_config: &ServiceConfig) -> Text {
+        match self.texts.get(key) {
+            Some(dynamic) => dynamic.merge(service_config.text.as_ref()),
+            None => service_config.text.clone().unwrap_or_default(),
+        }
+    }
+
+    // Send a registration's current TXT record to the backend, re-registering when it
+    // can't be updated in place.
+    fn push_text(&mut self, key: &str) {
+        let Some(registration) = self.registrations.get(key) else {
+            return;
+        };
+        // A failed or unhealthy registration picks the new record up when it registers
+        if !registration.state.in_backend() {
+            return;
+        }
+        let text = self.text_of(key, &registration.config);
+        match self.backend.update_txt(key, &text) {
+            Ok(()) => log::info!(service = key; "Updated TXT record"),
+            Err(e) => {
+                log::warn!(service = key, error:% = e; "Updating TXT record failed, re-registering");
+                let port = Some(registration.port).filter(|port| *port != 0);
+                let (config, transient) = (registration.config.clone(), registration.transient);
+                self.register(key.to_string(), config, port, 0, transient);
+            }
+        }
+    }
+
+    // Re-read changed text files and start the text commands that are due.
+    fn poll_texts(&mut self) {
+        let mut changed = Vec::new();
+        for (key, dynamic) in &mut self.texts {
+            if dynamic.poll_file(key) {
+                changed.push(key.clone());
+            }
+            dynamic.run_if_due(key, &self.events);
+        }
+        for key in changed {
+            self.push_text(&key);
+        }
+    }
+
+    fn text_finished(&mut self, key: &str, result: std::result::Result<Text, String>) {
+        let Some(dynamic) = self.texts.get_mut(key) else {
+            return;
+        };
+        if dynamic.finished(key, result) {
+            self.push_text(key);
+        }
+    }
+
+    // Start, restart or stop checking an entry's health to match its config.
+    fn watch_health(&mut self, key: &str, service_config: &ServiceConfig) {
+        match &service_config.health {
+            Some(config) => {
+                if !self
+                    .healths
+                    .get(key)
+                    .is_some_and(|health| health.matches(config))
+                {
+                    self.healths.insert(key.to_string(), Health::new(config));
+                }
+            }
+            None => {
+                self.healths.remove(key);
+            }
+        }
+    }
+
+    // Start the health checks that are due.
+    fn poll_health(&mut self) {
+        for (key, health) in &mut self.healths {
+            health.check_if_due(key, &self.events);
+        }
+    }
+
+    // Start, restart or stop watching an entry's process to match its config.
+    fn watch_process(&mut self, key: &str, service_config: &ServiceConfig) {
+        if self
+            .processes
+            .get(key)
+            .is_some_and(|watch| watch.matches(service_config))
+        {
+            return;
+        }
+        match ProcessWatch::new(service_config) {
+            Some(watch) => {
+                self.processes.insert(key.to_string(), watch);
+            }
+            None => {
+                self.processes.remove(key);
+            }
+        }
+    }
+
+    fn unwatch(&mut self, key: &str) {
+        self.texts.remove(key);
+        self.healths.remove(key);
+        self.processes.remove(key);
+        self.children.remove(key);
+    }
+
+    // Look for the watched processes starting or exiting.
+    fn poll_processes(&mut self) {
+        let mut changed = Vec::new();
+        for (key, watch) in &mut self.processes {
+            match watch.poll() {
+                Some(true) => {
+                    log::info!(service = key.as_str(), process = watch.describe(); "Process started")
+                }
+                Some(false) => {
+                    log::info!(service = key.as_str(), process = watch.describe(); "Process exited")
+                }
+                None => continue,
+            }
+            changed.push(key.clone());
+        }
+        for key in changed {
+            self.reconcile(&key);
+        }
+    }
+
+    fn health_checked(&mut self, key: &str, result: std::result::Result<(), String>) {
+        let (Some(health), Some(registration)) =
+            (self.healths.get_mut(key), self.registrations.get(key))
+        else {
+            return;
+        };
+        match health.record(key, &registration.config.service_type, result) {
+            Some(true) => log::info!(service = key; "Health check passing"),
+            Some(false) => log::warn!(
+                service = key,
+                error = health.error.as_deref().unwrap_or_default();
+                "Health check failing"
+            ),
+            None => return,
+        }
+        self.reconcile(key);
+    }
+
+    // Why an entry shouldn't be advertised right now, if anything.
+    fn gate(&self, key: &str) -> Option<(RegistrationState, String)> {
+        if let Some(watch) = self.processes.get(key).filter(|watch| !watch.running()) {
+            return Some((
+                RegistrationState::Inactive,
+                format!("waiting for {}", watch.describe()),
+            ));
+        }
+        if let Some(health) = self.healths.get(key).filter(|health| !health.healthy()) {
+            return Some((
+                RegistrationState::Unhealthy,
+                health.error.clone().unwrap_or_default(),
+            ));
+        }
+        None
+    }
+
+    // Withdraw or register an entry after its process or health check changed.
+    fn reconcile(&mut self, key: &str) {
+        let gate = self.gate(key);
+        let Some(registration) = self.registrations.get_mut(key) else {
+            return;
+        };
+        match gate {
+            Some((state, error)) => {
+                if registration.state.in_backend() {
+                    self.backend.unregister(key);
+                    log::info!(service = key, state:% = state; "Unregistered");
+                }
+                registration.state = state;
+                registration.error = Some(error);
+                registration.failures = 0;
+                registration.retry_at = None;
+            }
+            // A failed registration keeps waiting for its retry
+            None if !registration.state.in_backend()
+                && registration.state != RegistrationState::Failed =>
+            {
+                let port = Some(registration.port).filter(|port| *port != 0);
+                let (config, transient) = (registration.config.clone(), registration.transient);
+                self.register(key.to_string(), config, port, 0, transient);
+            }
+            None => {}
+        }
+    }
+
+    fn status(&self, key: &str, registration: &Registration) -> ServiceStatus {
+        ServiceStatus {
+            key: key.to_string(),
+            name: self.name_of(key, &registration.config),
+            service_type: registration.config.service_type.clone(),
+            port: registration.port,
+            text: self.text_of(key, &registration.config),
+            state: registration.state,
+            transient: registration.transient,
+            file: self
+                .files
+                .get(key)
+                .filter(|_| !registration.transient)
+                .cloned(),
+            error: registration.error.clone(),
+            failures: registration.failures,
+        }
+    }
+
+    fn status_of(&self, key: &str) -> std::result::Result<ServiceStatus, ControlError> {
+        self.registrations
+            .get(key)
+            .map(|registration| self.status(key, registration))
+            .ok_or_else(|| ControlError::NotFound(key.to_string()))
+    }
+
+    // Answer a control request from the API or the IPC channel, going through the same register and
+    // unregister paths as a reload.
+    fn handle(&mut self, request: Request) -> Reply {
+        match request {
+            Request::List => Ok(Response::Services(
+                self.registrations
+                    .iter()
+                    .map(|(key, registration)| self.status(key, registration))
+                    .collect(),
+            )),
+            Request::Get { key } => self.status_of(&key).map(Response::Service),
+            Request::Add { key, config } => {
+                if self.registrations.contains_key(&key) {
+                    return Err(ControlError::Exists(key));
+                }
+                let mut diagnostics = validate::validate_service(&key, &config);
+                // The API is open to every local user, unlike the config file. A health
+                // check would have the daemon probe any host it's told to
+                if config.text_file.is_some()
+                    || config.text_command.is_some()
+                    || config.health.is_some()
+                    || config.port_file.is_some()
+                    || config.exec.is_some()
+                {
+                    diagnostics.push(Diagnostic::error(
+                        format!("services.{}", key),
+                        "text_file, text_command, health, port_file and exec can only be set in the config file"
+                            .into(),
+                    ));
+                }
+                let identity =
+                    |c: &ServiceConfig| (c.name.to_lowercase(), c.service_type.to_lowercase());
+                if let Some((other, _)) = self
+                    .registrations
+                    .iter()
+                    .find(|(_, registration)| identity(&registration.config) == identity(&config))
+                {
+                    diagnostics.push(Diagnostic::error(
+                        format!("services.{}", key),
+                        format!(
+                            "\"{}\" of type {} is already advertised by services.{}",
+                            config.name, config.service_type, other
+                        ),
+                    ));
+                }
+                if validate::has_errors(&diagnostics) {
+                    return Err(ControlError::Invalid(diagnostics));
+                }
+                for diagnostic in &diagnostics {
+                    log::warn!("{}", diagnostic);
+                }
+                self.register(key.clone(), *config, None, 0, true);
+                self.status_of(&key).map(Response::Service)
+            }
+            Request::Remove { key } => {
+                let registration = self
+                    .registrations
+                    .remove(&key)
+                    .ok_or_else(|| ControlError::NotFound(key.clone()))?;
+                if registration.state.in_backend() {
+                    self.backend.unregister(&key);
+                }
+                self.unwatch(&key);
+                if registration.transient && self.state.names.remove(&key).is_some() {
+                    self.save_state();
+                }
+                log::info!(service = key.as_str(); "Unregistered");
+                Ok(Response::Removed)
+            }
+            Request::UpdateTxt { key, text } => {
+                let mut diagnostics = Vec::new();
+                validate::check_text(&format!("services.{}", key), &text, &mut diagnostics);
+                if validate::has_errors(&diagnostics) {
+                    return Err(ControlError::Invalid(diagnostics));
+                }
+                let registration = self
+                    .registrations
+                    .get_mut(&key)
+                    .ok_or_else(|| ControlError::NotFound(key.clone()))?;
+                registration.config.text = Some(text);
+                self.push_text(&key);
+                self.status_of(&key).map(Response::Service)
+            }
+            Request::SetGroup { group, enabled } => {
+                let members: Vec<String> = self
+                    .registrations
+                    .iter()
+                    .filter(|(_, registration)| registration.config.group.as_ref() == Some(&group))
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                if members.is_empty() {
+                    return Err(ControlError::NoGroup(group));
+                }
+                if enabled {
+                    log::info!(group = group.as_str(); "Enabling group");
+                } else {
+                    log::info!(group = group.as_str(); "Disabling group");
+                }
+                self.groups.insert(group, enabled);
+                for key in &members {
+                    self.switch(key);
+                }
+                self.summary();
+                members
+                    .iter()
+                    .map(|key| self.status_of(key))
+                    .collect::<std::result::Result<_, _>>()
+                    .map(Response::Services)
+            }
+            Request::Reload => {
+                self.reload().map_err(|e| {
+                    log::error!(error:% = e; "Reload failed, keeping the running services");
+                    ControlError::Failed(e.to_string())
+                })?;
+                self.handle(Request::List)
+            }
+        }
+    }
+
+    // Pick up state changes the backend made on its own, e.g. probing finished or
+    // another host claimed the name.
+    fn refresh(&mut self) {
+        let mut conflicted = Vec::new();
+        for (key, registration) in &mut self.registrations {
+            if !registration.state.in_backend() {
+                continue;
+            }
+            let state = self.backend.state(key);
+            if state == registration.state {
+                continue;
+            }
+            registration.state = state;
+            match state {
+                RegistrationState::Conflicted => conflicted.push(key.clone()),
+                RegistrationState::Registered => log::info!(service = key.as_str(); "Registered"),
+                _ => log::info!(service = key.as_str(), state:% = state; "State changed"),
+            }
+        }
+        for key in conflicted {
+            let Some(registration) = self.registrations.get(&key) else {
+                continue;
+            };
+            let service_type = registration.config.service_type.as_str();
+            metrics::conflict(&key, service_type);
+            log::error!(
+                service = key.as_str(),
+                name = self.name_of(&key, &registration.config),
+                type = service_type;
+                "Name is already taken by another host"
+            );
+            self.rename(&key);
+        }
+    }
+
+    // One line with how many registrations are in each state, plus why any failed.
+    fn summary(&self) {
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        for (_, state) in self.states() {
+            *counts.entry(state.to_string()).or_default() += 1;
+        }
+        let counts: Vec<String> = counts
+            .iter()
+            .map(|(state, count)| format!("{} {}", count, state))
+            .collect();
+        log::info!(
+            "{} services: {}",
+            self.registrations.len(),
+            if counts.is_empty() {
+                "none".to_string()
+            } else {
+                counts.join(", ")
+            }
+        );
+        let now = Instant::now();
+        for (key, registration) in &self.registrations {
+            if let (Some(error), Some(retry_at)) = (&registration.error, registration.retry_at) {
+                log::warn!(
+                    service = key.as_str(),
+                    attempt = registration.failures,
+                    retry_in:% = format_args!("{}s", retry_at.saturating_duration_since(now).as_secs()),
+                    error = error.as_str();
+                    "Not registered"
+                );
+            }
+        }
+    }
+
+    // Advertise until Event::Stop arrives, reloading on Event::Reload or when the file
+    // changes. Call shutdown afterwards to withdraw everything.
+    pub fn run(&mut self, events: &mpsc::Receiver<Event>) {
+        let mut next_tick = Instant::now() + TICK;
+        loop {
+            // Wake up early for a retry that falls due before the next tick
+            let wake = self
+                .registrations
+                .values()
+                .filter_map(|registration| registration.retry_at)
+                .fold(next_tick, Instant::min);
+            let wait = wake.saturating_duration_since(Instant::now());
+            let result = match events.recv_timeout(wait) {
+                Ok(Event::Stop) | Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                Ok(Event::Reload) => self.reload(),
+                Ok(Event::Control(request, reply)) => {
+                    let _ = reply.send(self.handle(request));
+                    Ok(())
+                }
+                Ok(Event::Text(key, result)) => {
+                    self.text_finished(&key, result);
+                    Ok(())
+                }
+                Ok(Event::Health(key, result)) => {
+                    self.health_checked(&key, result);
+                    Ok(())
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => Ok(()),
+            };
+            if let Err(e) = result {
+                log::error!(error:% = e; "Reload failed, keeping the running services");
+            }
+            self.retry_failed();
+            // A steady stream of events mustn't hold the periodic work back
+            if Instant::now() >= next_tick {
+                next_tick = Instant::now() + TICK;
+                self.tick();
+            }
+        }
+    }
+
+    fn tick(&mut self) {
+        self.refresh();
+        self.poll_texts();
+        self.poll_health();
+        self.poll_processes();
+        self.poll_children();
+        if let Err(e) = self.reload_if_changed() {
+            log::error!(error:% = e; "Reload failed, keeping the running services");
+        }
+    }
+
+    // Unregister every service (the backends send TTL=0 goodbyes) and release the
+    // backend on a helper thread, so a stuck daemon can't hold up the stop forever. The
+    // exec commands are stopped next, once their services are no longer advertised or
+    // the wait for that ran out. `progress` gets the number of services still
+    // registered, at least every SHUTDOWN_PROGRESS_INTERVAL. Returns false if
+    // SHUTDOWN_TIMEOUT ran out before the backend was done.
+    pub fn shutdown(self, mut progress: impl FnMut(usize)) -> bool {
+        let Advertiser {
+            mut backend,
+            registrations,
+            api,
+            ipc,
+            exporter,
+            children,
+            ..
+        } = self;
+        // Nothing can add services behind our back from here on
+        drop(api);
+        drop(ipc);
+        // A scrape would wait on a worker loop that is no longer answering
+        drop(exporter);
+        let keys: Vec<String> = registrations
+            .into_iter()
+            .filter(|(_, registration)| registration.state.in_backend())
+            .map(|(key, _)| key)
+            .collect();
+        let mut remaining = keys.len();
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            for key in keys {
+                backend.unregister(&key);
+                log::info!(service = key.as_str(); "Unregistered");
+                let _ = done_tx.send(());
+            }
+            drop(backend);
+        });
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        progress(remaining);
+        let withdrawn = loop {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            match done_rx.recv_timeout(wait.min(SHUTDOWN_PROGRESS_INTERVAL)) {
+                Ok(()) => remaining -= 1,
+                // The sender goes away once the backend is gone
+                Err(mpsc::RecvTimeoutError::Disconnected) => break true,
+                Err(mpsc::RecvTimeoutError::Timeout) if wait.is_zero() => {
+                    log::warn!(
+                        "Gave up waiting after {:?}, {} services still registered",
+                        SHUTDOWN_TIMEOUT,
+                        remaining
+                    );
+                    break false;
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+            }
+            progress(remaining);
+        };
+        // Even with the backend stuck: the commands would outlive windns-sd otherwise.
+        // Each gets a few seconds to exit before it is killed.
+        let (stopped_tx, stopped_rx) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            child::stop_all(children.into_values());
+            drop(stopped_tx);
+        });
+        while let Err(mpsc::RecvTimeoutError::Timeout) =
+            stopped_rx.recv_timeout(SHUTDOWN_PROGRESS_INTERVAL)
+        {
+            progress(remaining);
+        }
+        withdrawn
+    }
+}
+
+fn same_except_text(a: &ServiceConfig, b: &ServiceConfig) -> bool {
+    let without_text = |c: &ServiceConfig| ServiceConfig {
+        text: None,
+        text_file: None,
+        text_command: None,
+        text_interval: None,
+        ..c.clone()
+    };
+    without_text(a) == without_text(b)
+}
+
+fn modified(config_path: &Path) -> Vec<(PathBuf, Option<SystemTime>)> {
+    // An unreadable config.d is reported by the reload
+    Settings::files(config_path)
+        .unwrap_or_else(|_| vec![config_path.to_path_buf()])
+        .into_iter()
+        .map(|path| {
+            let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+            (path, modified)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{memory::MemoryBackend, Discovered};
+    use std::{
+        collections::HashMap,
+        env, process,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    // MemoryBackend, noting every call made to it. While `pending` is set it reports
+    // registrations as still probing, while `failing` is set it refuses them.
+    struct Recording {
+        backend: MemoryBackend,
+        calls: Arc<Mutex<Vec<String>>>,
+        pending: Arc<AtomicBool>,
+        failing: Arc<AtomicBool>,
+    }
+
+    impl Recording {
+        fn note(&self, call: &str, key: &str) {
+            self.calls.lock().unwrap().push(format!("{} {}", call, key));
+        }
+    }
+
+    impl Backend for Recording {
+        fn register(&mut self, key: &str, advertisement: &Advertisement) -> Result<()> {
+            self.note("register", key);
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(Error::Registration {
+                    key: key.to_string(),
+                    message: "failing".into(),
+                });
+            }
+            self.backend.register(key, advertisement)
+        }
+
+        fn update_txt(&mut self, key: &str, text: &HashMap<String, String>) -> Result<()> {
+            self.note("update_txt", key);
+            self.backend.update_txt(key, text)
+        }
+
+        fn unregister(&mut self, key: &str) {
+            self.note("unregister", key);
+            self.backend.unregister(key)
+        }
+
+        fn browse(&mut self, service_type: &str, timeout: Duration) -> Result<Vec<Discovered>> {
+            self.backend.browse(service_type, timeout)
+        }
+
+        fn state(&self, key: &str) -> RegistrationState {
+            if self.pending.load(Ordering::SeqCst) {
+                RegistrationState::Pending
+            } else {
+                self.backend.state(key)
+            }
+        }
+    }
+
+    // An Advertiser on a config file in a directory of its own.
+    struct Fixture {
+        dir: PathBuf,
+        path: PathBuf,
+        advertiser: Advertiser,
+        calls: Arc<Mutex<Vec<String>>>,
+        pending: Arc<AtomicBool>,
+        failing: Arc<AtomicBool>,
+        _events: mpsc::Receiver<Event>,
+    }
+
+    const HEADER: &str = "backend = \"memory\"\n[ipc]\nenabled = false\n";
+
+    impl Fixture {
+        fn new(name: &str, services: &str) -> Self {
+            Self::with_backend(name, services, false, false)
+        }
+
+        // With the backend reporting registrations as pending or refusing them from the
+        // start.
+        fn with_backend(name: &str, services: &str, pending: bool, failing: bool) -> Self {
+            let dir = env::temp_dir().join(format!("windns-sd-{}-{}", process::id(), name));
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("config.toml");
+            fs::write(&path, format!("{}{}", HEADER, services)).unwrap();
+            let calls = Arc::new(Mutex::new(Vec::new()));
+            let pending = Arc::new(AtomicBool::new(pending));
+            let failing = Arc::new(AtomicBool::new(failing));
+            let backend = Box::new(Recording {
+                backend: MemoryBackend::default(),
+                calls: calls.clone(),
+                pending: pending.clone(),
+                failing: failing.clone(),
+            });
+            let (events, receiver) = mpsc::channel();
+            let settings = Settings::load(&path).unwrap();
+            let advertiser = Advertiser::with_backend(&path, settings, backend, &events).unwrap();
+            Fixture {
+                dir,
+                path,
+                advertiser,
+                calls,
+                pending,
+                failing,
+                _events: receiver,
+            }
+        }
+
+        fn reload(&mut self, services: &str) {
+            fs::write(&self.path, format!("{}{}", HEADER, services)).unwrap();
+            self.advertiser.reload().unwrap();
+        }
+
+        // The backend calls since the last look.
+        fn calls(&self) -> Vec<String> {
+            std::mem::take(&mut *self.calls.lock().unwrap())
+        }
+
+        // run() for `duration` while events keep arriving every 50ms, faster than TICK.
+        fn run_busy(&mut self, duration: Duration) {
+            let (events, receiver) = mpsc::channel();
+            let sender = thread::spawn(move || {
+                let end = Instant::now() + duration;
+                while Instant::now() < end {
+                    let _ = events.send(Event::Health("none".into(), Ok(())));
+                    thread::sleep(Duration::from_millis(50));
+                }
+                let _ = events.send(Event::Stop);
+            });
+            self.advertiser.run(&receiver);
+            sender.join().unwrap();
+        }
+
+        fn states(&self) -> Vec<(&str, RegistrationState)> {
+            self.advertiser.states().collect()
+        }
+
+        fn advertised(&mut self, service_type: &str) -> Vec<Discovered> {
+            self.advertiser
+                .backend
+                .browse(service_type, Duration::ZERO)
+                .unwrap()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    const TWO: &str = r#"
+        [services.a]
+        name = "A"
+        type = "_http._tcp"
+        port = 80
+        text = { v = "1" }
+        [services.b]
+        name = "B"
+        type = "_ssh._tcp"
+        port = 22
+    "#;
+
+    #[test]
+    fn unchanged_entries_stay_registered() {
+        let mut fixture = Fixture::new("unchanged", TWO);
+        assert_eq!(fixture.calls(), ["register a", "register b"]);
+        fixture.reload(TWO);
+        assert_eq!(fixture.calls(), Vec::<String>::new());
+        assert_eq!(
+            fixture.states(),
+            [
+                ("a", RegistrationState::Registered),
+                ("b", RegistrationState::Registered)
+            ]
+        );
+    }
+
+    #[test]
+    fn text_change_updates_in_place() {
+        let mut fixture = Fixture::new("text", TWO);
+        fixture.calls();
+        fixture.reload(&TWO.replace(r#"v = "1""#, r#"v = "2""#));
+        assert_eq!(fixture.calls(), ["update_txt a"]);
+        let advertised = fixture.advertised("_http._tcp");
+        assert_eq!(advertised[0].text["v"], "2");
+    }
+
+    #[test]
+    fn port_or_name_change_registers_again() {
+        let mut fixture = Fixture::new("reregister", TWO);
+        fixture.calls();
+        fixture.reload(&TWO.replace("port = 80", "port = 8080"));
+        assert_eq!(fixture.calls(), ["register a"]);
+        assert_eq!(fixture.advertised("_http._tcp")[0].port, 8080);
+        fixture.reload(
+            &TWO.replace("port = 80", "port = 8080")
+                .replace("\"A\"", "\"A2\""),
+        );
+        assert_eq!(fixture.calls(), ["register a"]);
+        assert_eq!(fixture.advertised("_http._tcp")[0].name, "A2");
+    }
+
+    #[test]
+    fn removed_entries_are_unregistered() {
+        let mut fixture = Fixture::new("removed", TWO);
+        fixture.calls();
+        let (only_a, _) = TWO.split_once("[services.b]").unwrap();
+        fixture.reload(only_a);
+        assert_eq!(fixture.calls(), ["unregister b"]);
+        assert_eq!(fixture.states(), [("a", RegistrationState::Registered)]);
+        assert!(fixture.advertised("_ssh._tcp").is_empty());
+    }
+
+    #[test]
+    fn transient_entries_survive_reload() {
+        let mut fixture = Fixture::new("transient", TWO);
+        fixture.calls();
+        let config = Box::new(ServiceConfig {
+            name: "T".into(),
+            service_type: "_ipp._tcp".into(),
+            port: 631,
+            ..Default::default()
+        });
+        let added = fixture.advertiser.handle(Request::Add {
+            key: "t".into(),
+            config,
+        });
+        assert!(added.is_ok());
+        assert_eq!(fixture.calls(), ["register t"]);
+        let (only_a, _) = TWO.split_once("[services.b]").unwrap();
+        fixture.reload(only_a);
+        assert_eq!(fixture.calls(), ["unregister b"]);
+        assert_eq!(
+            fixture.states(),
+            [
+                ("a", RegistrationState::Registered),
+                ("t", RegistrationState::Registered)
+            ]
+        );
+        assert_eq!(fixture.advertised("_ipp._tcp").len(), 1);
+    }
+    #[test]
+    fn busy_event_channel_still_refreshes() {
+        let mut fixture = Fixture::with_backend("busy", TWO, true, false);
+        assert_eq!(fixture.states()[0], ("a", RegistrationState::Pending));
+        // Probing finished
+        fixture.pending.store(false, Ordering::SeqCst);
+        fixture.run_busy(TICK * 2);
+        assert_eq!(
+            fixture.states(),
+            [
+                ("a", RegistrationState::Registered),
+                ("b", RegistrationState::Registered)
+            ]
+        );
+    }
+    #[test]
+    fn busy_event_channel_still_retries() {
+        let services = format!(
+            "[retry]\ninitial_delay = 0.2\njitter = 0.0\n{}",
+            TWO.split_once("[services.b]").unwrap().0
+        );
+        let mut fixture = Fixture::with_backend("retry", &services, false, true);
+        assert_eq!(fixture.calls(), ["register a"]);
+        assert_eq!(fixture.states(), [("a", RegistrationState::Failed)]);
+        fixture.failing.store(false, Ordering::SeqCst);
+        // Well before the first tick: the retry is due after 0.2s
+        fixture.run_busy(TICK / 2);
+        assert_eq!(fixture.calls(), ["register a"]);
+        assert_eq!(fixture.states(), [("a", RegistrationState::Registered)]);
+    }
+    #[test]
+    fn added_entries_cant_have_a_health_check() {
+        let mut fixture = Fixture::new("add-health", TWO);
+        fixture.calls();
+        let config = Box::new(ServiceConfig {
+            name: "T".into(),
+            service_type: "_ipp._tcp".into(),
+            port: 631,
+            health: Some(crate::health::HealthConfig {
+                tcp: Some("192.0.2.1:22".into()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let added = fixture.advertiser.handle(Request::Add {
+            key: "t".into(),
+            config,
+        });
+        assert!(matches!(added, Err(ControlError::Invalid(_))));
+        assert_eq!(fixture.calls(), Vec::<String>::new());
+        assert!(!fixture.states().iter().any(|(key, _)| *key == "t"));
+    }
+}
@@ -0,0 +1,65 @@
+use crate::validate::Diagnostic;
+use std::{io, path::PathBuf};
+
+// Everything that can stop windns-sd. Each variant maps to its own exit code so the
+// service control manager (or the shell) tells operators why it stopped.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to load {}: {source}", path.display())]
+    Config {
+        path: PathBuf,
+        source: Box<config::ConfigError>,
+    },
+    #[error("invalid config {}:{}", path.display(), list(diagnostics))]
+    Invalid {
+        path: PathBuf,
+        diagnostics: Vec<Diagnostic>,
+    },
+    #[error("failed to allocate a port for {key}: {source}")]
+    Port { key: String, source: io::Error },
+    #[error("failed to register {key}: {message}")]
+    Registration { key: String, message: String },
+    #[error("failed to browse for {service_type}: {message}")]
+    Browse {
+        service_type: String,
+        message: String,
+    },
+    #[error("failed to start the built-in mDNS responder: {0}")]
+    Responder(io::Error),
+    #[error("failed to install signal handler: {0}")]
+    Signal(io::Error),
+    #[error("failed to start the HTTP API: {0}")]
+    Api(io::Error),
+    #[error("failed to open the control channel: {0}")]
+    Ipc(io::Error),
+    #[error("failed to start the metrics endpoint: {0}")]
+    Metrics(io::Error),
+    #[cfg(windows)]
+    #[error("service control error: {0}")]
+    Service(#[from] windows_service::Error),
+}
+
+impl Error {
+    pub fn exit_code(&self) -> u32 {
+        match self {
+            Error::Config { .. } => 1,
+            Error::Port { .. } => 2,
+            Error::Registration { .. } => 3,
+            Error::Signal(_) => 4,
+            #[cfg(windows)]
+            Error::Service(_) => 5,
+            Error::Invalid { .. } => 6,
+            Error::Browse { .. } => 7,
+            Error::Responder(_) => 8,
+            Error::Api(_) => 9,
+            Error::Ipc(_) => 10,
+            Error::Metrics(_) => 11,
+        }
+    }
+}
+
+fn list(diagnostics: &[Diagnostic]) -> String {
+    diagnostics.iter().map(|d| format!("\n  {}", d)).collect()
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
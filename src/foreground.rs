@@ -0,0 +1,54 @@
+use crate::{
+    daemon::{Advertiser, Event},
+    error::{Error, Result},
+    logging,
+};
+use std::{path::Path, sync::mpsc};
+
+// SIGINT/SIGTERM stop, SIGHUP reloads; handled on a helper thread.
+#[cfg(unix)]
+fn event_channel() -> std::io::Result<(mpsc::Sender<Event>, mpsc::Receiver<Event>)> {
+    use signal_hook::{
+        consts::{SIGHUP, SIGINT, SIGTERM},
+        iterator::Signals,
+    };
+    let (event_tx, event_rx) = mpsc::channel();
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP])?;
+    let signal_tx = event_tx.clone();
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            let event = if signal == SIGHUP {
+                Event::Reload
+            } else {
+                Event::Stop
+            };
+            if signal_tx.send(event).is_err() {
+                break;
+            }
+        }
+    });
+    Ok((event_tx, event_rx))
+}
+
+// Ctrl+C stops; there is no reload signal on Windows, edit the file instead.
+#[cfg(windows)]
+fn event_channel() -> std::io::Result<(mpsc::Sender<Event>, mpsc::Receiver<Event>)> {
+    let (event_tx, event_rx) = mpsc::channel();
+    let signal_tx = event_tx.clone();
+    ctrlc::set_handler(move || {
+        let _ = signal_tx.send(Event::Stop);
+    })
+    .map_err(std::io::Error::other)?;
+    Ok((event_tx, event_rx))
+}
+
+// Run in the console instead of under the service control manager.
+pub fn run(config_path: &Path) -> Result<()> {
+    logging::init(config_path, true);
+    let (event_tx, event_rx) = event_channel().map_err(Error::Signal)?;
+    let mut advertiser = Advertiser::start(config_path, &event_tx)?;
+    advertiser.run(&event_rx);
+    log::info!("Shutting down, unregistering services");
+    advertiser.shutdown(|_| {});
+    Ok(())
+}
@@ -0,0 +1,205 @@
+// Health checks gating a [services.*] entry: it is only advertised while its check
+// passes, and withdrawn after enough failures in a row.
+use crate::{command, daemon::Event, metrics};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+// [services.*.health]. Exactly one of tcp, http or command is set.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HealthConfig {
+    // host:port that must accept a connection
+    pub tcp: Option<String>,
+    // http:// URL whose GET must answer with `status`
+    pub http: Option<String>,
+    pub status: u16,
+    // Shell command that must exit with 0
+    pub command: Option<String>,
+    // Seconds between checks, and how long one may take
+    pub interval: f64,
+    pub timeout: f64,
+    // Checks in a row it takes to come back, or to be withdrawn
+    pub healthy_threshold: u32,
+    pub unhealthy_threshold: u32,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            tcp: None,
+            http: None,
+            status: 200,
+            command: None,
+            interval: 10.0,
+            timeout: 2.0,
+            healthy_threshold: 2,
+            unhealthy_threshold: 3,
+        }
+    }
+}
+
+// Where one entry's checks stand. Until the first check comes back the entry counts
+// as unhealthy; that first result decides on its own, later ones need the thresholds.
+pub struct Health {
+    config: HealthConfig,
+    healthy: Option<bool>,
+    // Results in a row that disagree with `healthy`
+    streak: u32,
+    // When the next check runs; None while one is running
+    next_check: Option<Instant>,
+    pub error: Option<String>,
+}
+
+impl Health {
+    pub fn new(config: &HealthConfig) -> Self {
+        Health {
+            config: config.clone(),
+            healthy: None,
+            streak: 0,
+            next_check: Some(Instant::now()),
+            error: Some("waiting for the first health check".into()),
+        }
+    }
+
+    pub fn matches(&self, config: &HealthConfig) -> bool {
+        self.config == *config
+    }
+
+    pub fn healthy(&self) -> bool {
+        self.healthy == Some(true)
+    }
+
+    // Start a check on its own thread if one is due; the result comes back as
+    // Event::Health.
+    pub fn check_if_due(&mut self, key: &str, events: &mpsc::Sender<Event>) {
+        if self.next_check.is_none_or(|at| at > Instant::now()) {
+            return;
+        }
+        self.next_check = None;
+        let (key, config, events) = (key.to_string(), self.config.clone(), events.clone());
+        thread::spawn(move || {
+            let _ = events.send(Event::Health(key, check(&config)));
+        });
+    }
+
+    // Count a finished check and schedule the next one. Returns the new health when it
+    // flipped.
+    pub fn record(
+        &mut self,
+        key: &str,
+        service_type: &str,
+        result: Result<(), String>,
+    ) -> Option<bool> {
+        self.next_check = Some(Instant::now() + seconds(self.config.interval));
+        let passed = result.is_ok();
+        metrics::health_check(key, service_type, passed);
+        match result {
+            Ok(()) => self.error = None,
+            Err(e) => {
+                log::debug!(service = key, error = e.as_str(); "Health check failed");
+                self.error = Some(e);
+            }
+        }
+        let threshold = match self.healthy {
+            None => 1,
+            Some(healthy) if healthy == passed => {
+                self.streak = 0;
+                return None;
+            }
+            Some(true) => self.config.unhealthy_threshold,
+            Some(false) => self.config.healthy_threshold,
+        };
+        self.streak += 1;
+        if self.streak < threshold {
+            return None;
+        }
+        self.streak = 0;
+        self.healthy = Some(passed);
+        Some(passed)
+    }
+}
+
+fn seconds(seconds: f64) -> Duration {
+    Duration::try_from_secs_f64(seconds).unwrap_or(Duration::ZERO)
+}
+
+fn check(config: &HealthConfig) -> Result<(), String> {
+    let timeout = seconds(config.timeout);
+    if let Some(address) = &config.tcp {
+        connect(address, timeout).map(drop)
+    } else if let Some(url) = &config.http {
+        check_http(url, config.status, timeout)
+    } else if let Some(command) = &config.command {
+        let output = command::output(command, timeout).map_err(|e| e.to_string())?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(command::failure(&output))
+        }
+    } else {
+        Err("no check configured".into())
+    }
+}
+
+fn connect(address: &str, timeout: Duration) -> Result<TcpStream, String> {
+    let mut error = format!("{} did not resolve", address);
+    for address in address.to_socket_addrs().map_err(|e| e.to_string())? {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = format!("{}: {}", address, e),
+        }
+    }
+    Err(error)
+}
+
+// A bare HTTP/1.0 GET; only the status line is looked at.
+fn check_http(url: &str, status: u16, timeout: Duration) -> Result<(), String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or("only http:// URLs are supported")?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let address = if has_port(authority) {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+    let mut stream = connect(&address, timeout)?;
+    let _ = stream.set_read_timeout(Some(timeout));
+    let _ = stream.set_write_timeout(Some(timeout));
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: windns-sd\r\nConnection: close\r\n\r\n",
+        path, authority
+    )
+    .map_err(|e| e.to_string())?;
+    let mut line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut line)
+        .map_err(|e| e.to_string())?;
+    let code: u16 = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| format!("not an HTTP response: {:?}", line.trim()))?;
+    if code == status {
+        Ok(())
+    } else {
+        Err(format!("status {}, expected {}", code, status))
+    }
+}
+
+// host:port or [v6]:port, as opposed to a bare host or [v6]
+pub fn has_port(address: &str) -> bool {
+    address
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| port.parse::<u16>().is_ok())
+}
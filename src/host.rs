@@ -0,0 +1,145 @@
+use std::env;
+
+// This computer's short host name, without any domain.
+pub fn hostname() -> String {
+    #[cfg(windows)]
+    let name = env::var("COMPUTERNAME").ok();
+    #[cfg(not(windows))]
+    let name = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .ok()
+        .or_else(|| env::var("HOSTNAME").ok());
+    name.as_deref()
+        .map(str::trim)
+        .and_then(|name| name.split('.').next())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("windns-sd")
+        .to_string()
+}
+
+// The name people know this computer by: COMPUTERNAME on Windows, the pretty host name
+// (hostnamectl's, in /etc/machine-info) elsewhere, or the host name if there is none.
+pub fn computer_name() -> String {
+    #[cfg(windows)]
+    let name = env::var("COMPUTERNAME").ok();
+    #[cfg(not(windows))]
+    let name = std::fs::read_to_string("/etc/machine-info")
+        .ok()
+        .and_then(|info| {
+            info.lines()
+                .find_map(|line| line.strip_prefix("PRETTY_HOSTNAME="))
+                .map(|name| name.trim().trim_matches(['"', '\'']).to_string())
+        });
+    name.filter(|name| !name.trim().is_empty())
+        .unwrap_or_else(hostname)
+}
+
+// A network interface's hardware address as aa:bb:cc:dd:ee:ff. Interfaces are named as
+// in `ip link` on Linux, and by connection name ("Ethernet") or adapter GUID on Windows.
+#[cfg(target_os = "linux")]
+pub fn mac_address(interface: &str) -> Option<String> {
+    if interface.is_empty() || interface.contains('/') || interface.starts_with('.') {
+        return None;
+    }
+    std::fs::read_to_string(format!("/sys/class/net/{}/address", interface))
+        .ok()
+        .map(|address| address.trim().to_lowercase())
+        .filter(|address| !address.is_empty())
+}
+
+#[cfg(windows)]
+pub fn mac_address(interface: &str) -> Option<String> {
+    adapter(interface, |adapter| {
+        let len = (adapter.PhysicalAddressLength as usize).min(adapter.PhysicalAddress.len());
+        let address: Vec<String> = adapter.PhysicalAddress[..len]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        Some(address.join(":")).filter(|address| !address.is_empty())
+    })
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+pub fn mac_address(_interface: &str) -> Option<String> {
+    None
+}
+
+// The index IPv6 knows an interface by, for the names if_addrs reports: the kernel's
+// name elsewhere, the adapter GUID on Windows.
+#[cfg(unix)]
+pub fn interface_index(interface: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(interface).ok()?;
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    Some(index).filter(|index| *index != 0)
+}
+
+#[cfg(windows)]
+pub fn interface_index(interface: &str) -> Option<u32> {
+    adapter(interface, |adapter| {
+        Some(adapter.Ipv6IfIndex).filter(|index| *index != 0)
+    })
+}
+
+// Look `f` up on the adapter with this connection name or adapter GUID.
+#[cfg(windows)]
+fn adapter<T>(
+    interface: &str,
+    f: impl Fn(&windows_sys::Win32::NetworkManagement::IpHelper::IP_ADAPTER_ADDRESSES_LH) -> Option<T>,
+) -> Option<T> {
+    use std::{ffi::CStr, ptr};
+    use windows_sys::Win32::{
+        Foundation::{ERROR_BUFFER_OVERFLOW, ERROR_SUCCESS},
+        NetworkManagement::IpHelper::{
+            GetAdaptersAddresses, GAA_FLAG_SKIP_ANYCAST, GAA_FLAG_SKIP_DNS_SERVER,
+            GAA_FLAG_SKIP_MULTICAST, GAA_FLAG_SKIP_UNICAST, IP_ADAPTER_ADDRESSES_LH,
+        },
+        Networking::WinSock::AF_UNSPEC,
+    };
+    let flags = GAA_FLAG_SKIP_UNICAST
+        | GAA_FLAG_SKIP_ANYCAST
+        | GAA_FLAG_SKIP_MULTICAST
+        | GAA_FLAG_SKIP_DNS_SERVER;
+    let mut size = 16 * 1024;
+    // u64s so the adapter structs the call writes are aligned
+    let mut buffer: Vec<u64>;
+    loop {
+        buffer = vec![0; (size as usize).div_ceil(8)];
+        let result = unsafe {
+            GetAdaptersAddresses(
+                AF_UNSPEC as u32,
+                flags,
+                ptr::null(),
+                buffer.as_mut_ptr().cast(),
+                &mut size,
+            )
+        };
+        match result {
+            ERROR_SUCCESS => break,
+            ERROR_BUFFER_OVERFLOW => continue,
+            _ => return None,
+        }
+    }
+    let mut adapter = buffer.as_ptr() as *const IP_ADAPTER_ADDRESSES_LH;
+    while let Some(current) = unsafe { adapter.as_ref() } {
+        let friendly_name = unsafe { wide_string(current.FriendlyName) };
+        let adapter_name = unsafe { CStr::from_ptr(current.AdapterName as *const _) };
+        if friendly_name.eq_ignore_ascii_case(interface)
+            || adapter_name
+                .to_string_lossy()
+                .eq_ignore_ascii_case(interface)
+        {
+            return f(current);
+        }
+        adapter = current.Next;
+    }
+    None
+}
+
+#[cfg(windows)]
+unsafe fn wide_string(s: windows_sys::core::PWSTR) -> String {
+    if s.is_null() {
+        return String::new();
+    }
+    let len = (0..).take_while(|i| *s.add(*i) != 0).count();
+    String::from_utf16_lossy(std::slice::from_raw_parts(s, len))
+}
@@ -0,0 +1,313 @@
+// Local control channel for the CLI: a Unix domain socket, or a named pipe on Windows.
+// Each connection carries one JSON request line and gets one JSON answer line back.
+use crate::{
+    control::{self, ControlError, Reply, Request, Response},
+    daemon::Event,
+    settings,
+    validate::Diagnostic,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+const MAX_REQUEST: u64 = 64 * 1024;
+// How long a client gets to send its request and to take the answer.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+// How long stopping waits for the listener thread before leaving it behind.
+const STOP_TIMEOUT: Duration = Duration::from_secs(1);
+
+// [ipc]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct IpcConfig {
+    pub enabled: bool,
+    // Socket path, or pipe name on Windows
+    pub path: Option<PathBuf>,
+}
+
+impl Default for IpcConfig {
+    fn default() -> Self {
+        IpcConfig {
+            enabled: true,
+            path: None,
+        }
+    }
+}
+
+impl IpcConfig {
+    // windns-sd.sock next to the config file, or \\.\pipe\windns-sd on Windows
+    pub fn endpoint(&self, config_path: &Path) -> PathBuf {
+        if let Some(path) = &self.path {
+            return path.clone();
+        }
+        if cfg!(windows) {
+            PathBuf::from(r"\\.\pipe\windns-sd")
+        } else {
+            settings::config_dir(config_path).join("windns-sd.sock")
+        }
+    }
+}
+
+// A Reply as it goes over the wire.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Answer {
+    Ok(Response),
+    Error {
+        message: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        diagnostics: Vec<Diagnostic>,
+    },
+}
+
+impl From<Reply> for Answer {
+    fn from(reply: Reply) -> Self {
+        match reply {
+            Ok(response) => Answer::Ok(response),
+            Err(e) => Answer::Error {
+                message: e.to_string(),
+                diagnostics: match e {
+                    ControlError::Invalid(diagnostics) => diagnostics,
+                    _ => Vec::new(),
+                },
+            },
+        }
+    }
+}
+
+// The listening side, run by the daemon; dropping it stops the listener.
+pub struct Ipc {
+    endpoint: PathBuf,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Ipc {
+    pub fn start(endpoint: &Path, events: mpsc::Sender<Event>) -> io::Result<Self> {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread = listen(endpoint, events, stopped.clone())?;
+        log::info!(endpoint:% = endpoint.display(); "Control channel listening");
+        Ok(Ipc {
+            endpoint: endpoint.to_path_buf(),
+            stopped,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Ipc {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wake the listener up from its blocking accept
+        let _ = connect(&self.endpoint);
+        if let Some(thread) = self.thread.take() {
+            let deadline = Instant::now() + STOP_TIMEOUT;
+            while !thread.is_finished() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+            if thread.is_finished() {
+                let _ = thread.join();
+            }
+        }
+        #[cfg(unix)]
+        let _ = std::fs::remove_file(&self.endpoint);
+    }
+}
+
+#[cfg(unix)]
+fn listen(
+    endpoint: &Path,
+    events: mpsc::Sender<Event>,
+    stopped: Arc<AtomicBool>,
+) -> io::Result<JoinHandle<()>> {
+    use std::os::unix::{
+        fs::PermissionsExt,
+        net::{UnixListener, UnixStream},
+    };
+    // A socket file nobody answers on is left over from a crash
+    if endpoint.exists() {
+        if UnixStream::connect(endpoint).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another instance is already running",
+            ));
+        }
+        std::fs::remove_file(endpoint)?;
+    }
+    let listener = UnixListener::bind(endpoint)?;
+    std::fs::set_permissions(endpoint, std::fs::Permissions::from_mode(0o600))?;
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            if stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            match stream {
+                // One thread per client, so a client that never sends holds up only itself
+                Ok(stream) => {
+                    let _ = stream.set_read_timeout(Some(CLIENT_TIMEOUT));
+                    let _ = stream.set_write_timeout(Some(CLIENT_TIMEOUT));
+                    let events = events.clone();
+                    thread::spawn(move || serve(&events, stream));
+                }
+                Err(e) => log::warn!(error:% = e; "Error accepting a control connection"),
+            }
+        }
+    }))
+}
+
+#[cfg(windows)]
+fn listen(
+    endpoint: &Path,
+    events: mpsc::Sender<Event>,
+    stopped: Arc<AtomicBool>,
+) -> io::Result<JoinHandle<()>> {
+    use std::{
+        fs::File,
+        os::windows::{
+            ffi::OsStrExt,
+            io::{AsRawHandle, FromRawHandle},
+        },
+        ptr,
+    };
+    use windows_sys::Win32::{
+        Foundation::{ERROR_PIPE_CONNECTED, INVALID_HANDLE_VALUE},
+        Storage::FileSystem::{
+            FlushFileBuffers, FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_DUPLEX,
+        },
+        System::Pipes::{
+            ConnectNamedPipe, CreateNamedPipeW, PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS,
+            PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+        },
+        System::IO::CancelSynchronousIo,
+    };
+    let name: Vec<u16> = endpoint
+        .as_os_str()
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect();
+    // The first instance fails if another process already owns the name
+    let create = move |first: bool| -> io::Result<(File, isize)> {
+        let open_mode = PIPE_ACCESS_DUPLEX
+            | if first {
+                FILE_FLAG_FIRST_PIPE_INSTANCE
+            } else {
+                0
+            };
+        let handle = unsafe {
+            CreateNamedPipeW(
+                name.as_ptr(),
+                open_mode,
+                PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                PIPE_UNLIMITED_INSTANCES,
+                4096,
+                4096,
+                0,
+                ptr::null(),
+            )
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((unsafe { File::from_raw_handle(handle as _) }, handle))
+    };
+    let mut pipe = create(true)?;
+    Ok(thread::spawn(move || loop {
+        let (file, handle) = pipe;
+        let connected = unsafe { ConnectNamedPipe(handle, ptr::null_mut()) } != 0
+            || io::Error::last_os_error().raw_os_error() == Some(ERROR_PIPE_CONNECTED as i32);
+        if stopped.load(Ordering::SeqCst) {
+            break;
+        }
+        if connected {
+            // One thread per client, so a client that never sends holds up only itself
+            let events = events.clone();
+            let serving = thread::spawn(move || {
+                serve(&events, &file);
+                // Closing the handle would discard what the client hasn't read yet
+                unsafe { FlushFileBuffers(handle) };
+            });
+            // Pipes have no timeouts: the blocked read or flush is cancelled instead
+            let thread_handle = serving.as_raw_handle() as isize;
+            let deadline = Instant::now() + CLIENT_TIMEOUT;
+            thread::spawn(move || {
+                while !serving.is_finished() {
+                    if Instant::now() >= deadline {
+                        unsafe { CancelSynchronousIo(thread_handle) };
+                    }
+                    thread::sleep(Duration::from_millis(50));
+                }
+            });
+        }
+        pipe = match create(false) {
+            Ok(pipe) => pipe,
+            Err(e) => {
+                log::error!(error:% = e; "Control channel stopped");
+                break;
+            }
+        };
+    }))
+}
+
+// Read one request, pass it to the worker loop and write the answer back.
+fn serve(events: &mpsc::Sender<Event>, mut stream: impl Read + Write) {
+    let mut line = String::new();
+    let read = BufReader::new((&mut stream).take(MAX_REQUEST)).read_line(&mut line);
+    let answer = match read.map_err(|e| e.to_string()).and_then(|_| {
+        serde_json::from_str::<Request>(&line).map_err(|e| format!("bad request: {}", e))
+    }) {
+        Ok(request) => Answer::from(control::send(events, request)),
+        Err(message) => Answer::Error {
+            message,
+            diagnostics: Vec::new(),
+        },
+    };
+    let mut reply = serde_json::to_string(&answer).unwrap_or_default();
+    reply.push('\n');
+    if let Err(e) = stream.write_all(reply.as_bytes()) {
+        log::warn!(error:% = e; "Error answering a control request");
+    }
+}
+
+#[cfg(unix)]
+fn connect(endpoint: &Path) -> io::Result<std::os::unix::net::UnixStream> {
+    std::os::unix::net::UnixStream::connect(endpoint)
+}
+
+// All pipe instances can be busy for a moment while the daemon answers someone else.
+#[cfg(windows)]
+fn connect(endpoint: &Path) -> io::Result<std::fs::File> {
+    use windows_sys::Win32::Foundation::ERROR_PIPE_BUSY;
+    let mut attempts = 0;
+    loop {
+        match std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(endpoint)
+        {
+            Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) && attempts < 20 => {
+                attempts += 1;
+                thread::sleep(std::time::Duration::from_millis(100));
+            }
+            result => return result,
+        }
+    }
+}
+
+// Send one request to the running daemon and wait for its answer.
+pub fn request(endpoint: &Path, request: &Request) -> io::Result<Answer> {
+    let mut stream = connect(endpoint)?;
+    let mut line = serde_json::to_string(request).map_err(io::Error::other)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    let mut answer = String::new();
+    BufReader::new(stream).read_line(&mut answer)?;
+    serde_json::from_str(&answer).map_err(io::Error::other)
+}
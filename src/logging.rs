@@ -0,0 +1,299 @@
+use crate::settings::{self, Settings};
+use log::{
+    kv::{self, Key, Value, VisitSource},
+    LevelFilter, Log, Metadata, Record,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsString,
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+// [logging]. Read once at startup; changes need a restart.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    pub level: LogLevel,
+    // Write to `path`, windns-sd.log next to the config file by default
+    pub file: bool,
+    pub path: Option<PathBuf>,
+    // Bytes before the file is rotated to windns-sd.log.1, and how many rotations to keep
+    pub max_size: u64,
+    pub keep: u32,
+    // Also send to /dev/log (syslog or journald); Linux only
+    pub syslog: bool,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: LogLevel::Info,
+            file: true,
+            path: None,
+            max_size: 10 * 1024 * 1024,
+            keep: 5,
+            syslog: false,
+        }
+    }
+}
+
+// A log file that is renamed to `<path>.1` (and older ones shifted up) once it grows
+// past max_size.
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: u32,
+}
+
+impl LogFile {
+    fn open(path: &Path, max_size: u64, keep: u32) -> io::Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(LogFile {
+            path: path.to_path_buf(),
+            size: file.metadata()?.len(),
+            file,
+            max_size,
+            keep,
+        })
+    }
+
+    fn rotated(&self, n: u32) -> PathBuf {
+        let mut path = OsString::from(&self.path);
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..self.keep).rev() {
+            let _ = fs::rename(self.rotated(n), self.rotated(n + 1));
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+struct Logger {
+    level: LevelFilter,
+    stderr: bool,
+    file: Option<Mutex<LogFile>>,
+    #[cfg(target_os = "linux")]
+    syslog: Option<std::os::unix::net::UnixDatagram>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = message(record);
+        if self.stderr {
+            eprintln!("{:<5} {}", record.level(), message);
+        }
+        if let Some(file) = &self.file {
+            let line = format!("{} {:<5} {}\n", timestamp(), record.level(), message);
+            let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(e) = file.write_line(&line) {
+                if self.stderr {
+                    eprintln!("Error writing {}: {}", file.path.display(), e);
+                }
+            }
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(socket) = &self.syslog {
+            // RFC 3164 framing with the daemon facility; journald reads it as well
+            let severity = match record.level() {
+                log::Level::Error => 3,
+                log::Level::Warn => 4,
+                log::Level::Info => 6,
+                log::Level::Debug | log::Level::Trace => 7,
+            };
+            let line = format!(
+                "<{}>windns-sd[{}]: {}",
+                3 * 8 + severity,
+                std::process::id(),
+                message
+            );
+            let _ = socket.send(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap_or_else(|e| e.into_inner()).file.flush();
+        }
+    }
+}
+
+// The message followed by its fields as logfmt, e.g.
+// `Registered service=smb type=_smb._tcp port=445`.
+fn message(record: &Record) -> String {
+    struct Fields(String);
+    impl<'kvs> VisitSource<'kvs> for Fields {
+        fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+            let value = value.to_string();
+            if value.is_empty() || value.contains([' ', '"', '=']) {
+                let _ = write!(self.0, " {}={:?}", key, value);
+            } else {
+                let _ = write!(self.0, " {}={}", key, value);
+            }
+            Ok(())
+        }
+    }
+    let mut fields = Fields(record.args().to_string());
+    let _ = record.key_values().visit(&mut fields);
+    fields.0
+}
+
+// UTC, RFC 3339 with second precision.
+fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, time) = (secs / 86400, secs % 86400);
+    // Days to a civil date, from Howard Hinnant's date algorithms
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+// Set up logging for the daemon from the [logging] section of `config_path`, falling
+// back to the defaults when the file can't be read (starting will report why).
+// `stderr` is set when running in the console.
+pub fn init(config_path: &Path, stderr: bool) {
+    let config = Settings::from_file(config_path)
+        .map(|settings| settings.logging)
+        .unwrap_or_default();
+    let mut problems = Vec::new();
+    let file = if config.file {
+        let path = config
+            .path
+            .clone()
+            .unwrap_or_else(|| settings::config_dir(config_path).join("windns-sd.log"));
+        match LogFile::open(&path, config.max_size, config.keep) {
+            Ok(file) => Some(Mutex::new(file)),
+            Err(e) => {
+                problems.push(format!("Can't open {}: {}", path.display(), e));
+                None
+            }
+        }
+    } else {
+        None
+    };
+    #[cfg(target_os = "linux")]
+    let syslog = match config.syslog.then(syslog_socket) {
+        Some(Err(e)) => {
+            problems.push(format!("Can't connect to /dev/log: {}", e));
+            None
+        }
+        Some(Ok(socket)) => Some(socket),
+        None => None,
+    };
+    #[cfg(not(target_os = "linux"))]
+    if config.syslog {
+        problems.push("logging.syslog is only supported on Linux".into());
+    }
+    install(Logger {
+        level: config.level.into(),
+        stderr,
+        file,
+        #[cfg(target_os = "linux")]
+        syslog,
+    });
+    for problem in problems {
+        log::warn!("{}", problem);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn syslog_socket() -> io::Result<std::os::unix::net::UnixDatagram> {
+    let socket = std::os::unix::net::UnixDatagram::unbound()?;
+    socket.connect("/dev/log")?;
+    Ok(socket)
+}
+
+// Warnings and errors to stderr, for the one-shot commands.
+pub fn init_console() {
+    install(Logger {
+        level: LevelFilter::Warn,
+        stderr: true,
+        file: None,
+        #[cfg(target_os = "linux")]
+        syslog: None,
+    });
+}
+
+fn install(logger: Logger) {
+    let level = logger.level;
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(level);
+    }
+}
@@ -0,0 +1,245 @@
+// Prometheus metrics. Counters live in a process-wide registry so the backends can
+// count without a handle; per-service gauges are read from the worker loop at scrape time.
+use crate::{
+    backend::RegistrationState,
+    control::{self, Request, Response, ServiceStatus},
+    daemon::Event,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{mpsc, Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+use tiny_http::{Header, Method, Server};
+
+const REGISTRATION_ATTEMPTS: &str = "windns_sd_registration_attempts_total";
+const CONFLICTS: &str = "windns_sd_conflicts_total";
+const RENAMES: &str = "windns_sd_renames_total";
+const RELOADS: &str = "windns_sd_reloads_total";
+const HEALTH_CHECKS: &str = "windns_sd_health_checks_total";
+const PACKETS_SENT: &str = "windns_sd_mdns_packets_sent_total";
+const PACKETS_RECEIVED: &str = "windns_sd_mdns_packets_received_total";
+
+const COUNTERS: &[(&str, &str)] = &[
+    (
+        REGISTRATION_ATTEMPTS,
+        "Registration attempts by service and result.",
+    ),
+    (
+        CONFLICTS,
+        "Times another host was found using a service's name.",
+    ),
+    (RENAMES, "Times a service was renamed after a conflict."),
+    (RELOADS, "Config reloads by result."),
+    (HEALTH_CHECKS, "Health checks by service and result."),
+    (PACKETS_SENT, "mDNS packets sent by the built-in responder."),
+    (
+        PACKETS_RECEIVED,
+        "mDNS packets received by the built-in responder.",
+    ),
+];
+
+type Labels = Vec<(&'static str, String)>;
+
+static COUNTER_VALUES: Mutex<BTreeMap<(&'static str, Labels), u64>> = Mutex::new(BTreeMap::new());
+
+fn increment(name: &'static str, labels: &[(&'static str, &str)]) {
+    let labels = labels.iter().map(|(k, v)| (*k, v.to_string())).collect();
+    let mut values = COUNTER_VALUES.lock().unwrap_or_else(|e| e.into_inner());
+    *values.entry((name, labels)).or_default() += 1;
+}
+
+fn result(success: bool) -> &'static str {
+    if success {
+        "success"
+    } else {
+        "failure"
+    }
+}
+
+pub fn registration_attempt(service: &str, service_type: &str, success: bool) {
+    increment(
+        REGISTRATION_ATTEMPTS,
+        &[
+            ("service", service),
+            ("type", service_type),
+            ("result", result(success)),
+        ],
+    );
+}
+
+pub fn conflict(service: &str, service_type: &str) {
+    increment(CONFLICTS, &[("service", service), ("type", service_type)]);
+}
+
+pub fn rename(service: &str, service_type: &str) {
+    increment(RENAMES, &[("service", service), ("type", service_type)]);
+}
+
+pub fn reload(success: bool) {
+    increment(RELOADS, &[("result", result(success))]);
+}
+
+pub fn health_check(service: &str, service_type: &str, passed: bool) {
+    increment(
+        HEALTH_CHECKS,
+        &[
+            ("service", service),
+            ("type", service_type),
+            ("result", result(passed)),
+        ],
+    );
+}
+
+pub fn packet_sent() {
+    increment(PACKETS_SENT, &[]);
+}
+
+pub fn packet_received() {
+    increment(PACKETS_RECEIVED, &[]);
+}
+
+// [metrics]: serve /metrics for Prometheus. Unlike [api] it can listen on any address,
+// everything it exposes is read-only.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub enabled: bool,
+    pub listen: SocketAddr,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            enabled: false,
+            listen: SocketAddr::from((Ipv4Addr::LOCALHOST, 9353)),
+        }
+    }
+}
+
+// The /metrics server thread; dropping it stops the server.
+pub struct Exporter {
+    server: Arc<Server>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Exporter {
+    pub fn start(config: &MetricsConfig, events: mpsc::Sender<Event>) -> io::Result<Self> {
+        let server = Arc::new(Server::http(config.listen).map_err(io::Error::other)?);
+        log::info!(address:% = config.listen; "Metrics listening");
+        let thread = thread::spawn({
+            let server = server.clone();
+            move || {
+                for request in server.incoming_requests() {
+                    let response = match (request.method(), request.url()) {
+                        (Method::Get, "/metrics") => {
+                            tiny_http::Response::from_string(render(&events)).with_header(
+                                Header::from_bytes(
+                                    &b"Content-Type"[..],
+                                    &b"text/plain; version=0.0.4"[..],
+                                )
+                                .expect("static header"),
+                            )
+                        }
+                        _ => tiny_http::Response::from_string("not found").with_status_code(404),
+                    };
+                    if let Err(e) = request.respond(response) {
+                        log::warn!(error:% = e; "Error answering metrics request");
+                    }
+                }
+            }
+        });
+        Ok(Exporter {
+            server,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// The text exposition format.
+fn render(events: &mpsc::Sender<Event>) -> String {
+    let mut out = String::new();
+    // The worker loop may be busy; the counters are still worth serving
+    if let Ok(Response::Services(services)) = control::send(events, Request::List) {
+        render_services(&mut out, &services);
+    }
+    let values = COUNTER_VALUES.lock().unwrap_or_else(|e| e.into_inner());
+    for (name, help) in COUNTERS {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for ((_, labels), value) in values.iter().filter(|((n, _), _)| n == name) {
+            let _ = writeln!(out, "{}{} {}", name, format_labels(labels), value);
+        }
+    }
+    out
+}
+
+fn render_services(out: &mut String, services: &[ServiceStatus]) {
+    let _ = writeln!(
+        out,
+        "# HELP windns_sd_services Services by registration state."
+    );
+    let _ = writeln!(out, "# TYPE windns_sd_services gauge");
+    for state in [
+        RegistrationState::Pending,
+        RegistrationState::Registered,
+        RegistrationState::Failed,
+        RegistrationState::Conflicted,
+        RegistrationState::Unhealthy,
+        RegistrationState::Inactive,
+        RegistrationState::Disabled,
+    ] {
+        let count = services.iter().filter(|s| s.state == state).count();
+        let _ = writeln!(out, "windns_sd_services{{state=\"{}\"}} {}", state, count);
+    }
+    let _ = writeln!(
+        out,
+        "# HELP windns_sd_services_configured Services from the config file, not added at runtime."
+    );
+    let _ = writeln!(out, "# TYPE windns_sd_services_configured gauge");
+    let configured = services.iter().filter(|s| !s.transient).count();
+    let _ = writeln!(out, "windns_sd_services_configured {}", configured);
+    let _ = writeln!(
+        out,
+        "# HELP windns_sd_service_state Each service's registration state, always 1."
+    );
+    let _ = writeln!(out, "# TYPE windns_sd_service_state gauge");
+    for service in services {
+        let labels = vec![
+            ("service", service.key.clone()),
+            ("type", service.service_type.clone()),
+            ("state", service.state.to_string()),
+        ];
+        let _ = writeln!(out, "windns_sd_service_state{} 1", format_labels(&labels));
+    }
+}
+
+fn format_labels(labels: &Labels) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
@@ -0,0 +1,153 @@
+// Tying an advertisement to a local process: `process` matches a running executable
+// by name, `pid_file` the process whose id the file holds. Looked up through /proc on
+// Linux and a process snapshot on Windows.
+use crate::settings::ServiceConfig;
+use std::{fs, path::PathBuf};
+
+pub struct ProcessWatch {
+    process: Option<String>,
+    pid_file: Option<PathBuf>,
+    // None until the first look
+    running: Option<bool>,
+}
+
+impl ProcessWatch {
+    // None for an entry without process or pid_file.
+    pub fn new(config: &ServiceConfig) -> Option<Self> {
+        if config.process.is_none() && config.pid_file.is_none() {
+            return None;
+        }
+        let mut watch = ProcessWatch {
+            process: config.process.clone(),
+            pid_file: config.pid_file.clone(),
+            running: None,
+        };
+        watch.poll();
+        Some(watch)
+    }
+
+    pub fn matches(&self, config: &ServiceConfig) -> bool {
+        self.process == config.process && self.pid_file == config.pid_file
+    }
+
+    pub fn running(&self) -> bool {
+        self.running == Some(true)
+    }
+
+    // Look again. Returns whether the process is running now if that changed.
+    pub fn poll(&mut self) -> Option<bool> {
+        let process_running = self.process.as_deref().is_none_or(name_running);
+        let pid_running = self.pid_file.as_ref().is_none_or(|path| {
+            fs::read_to_string(path)
+                .ok()
+                .and_then(|pid| pid.trim().parse().ok())
+                .is_some_and(pid_running)
+        });
+        let running = process_running && pid_running;
+        if self.running == Some(running) {
+            return None;
+        }
+        self.running = Some(running);
+        Some(running)
+    }
+
+    // What the entry waits for, e.g. `process sshd` or `the process in /run/app.pid`.
+    pub fn describe(&self) -> String {
+        match (&self.process, &self.pid_file) {
+            (Some(name), None) => format!("process {}", name),
+            (None, Some(path)) => format!("the process in {}", path.display()),
+            (Some(name), Some(path)) => {
+                format!("process {} with the id in {}", name, path.display())
+            }
+            (None, None) => "nothing".into(),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn pid_running(pid: u32) -> bool {
+    // A zombie has exited, it's only waiting to be reaped
+    fs::read_to_string(format!("/proc/{}/stat", pid)).is_ok_and(|stat| {
+        stat.rsplit_once(')')
+            .and_then(|(_, rest)| rest.trim_start().chars().next())
+            .is_some_and(|state| state != 'Z' && state != 'X')
+    })
+}
+
+// Matches the kernel's short name (comm, cut at 15 bytes) or the file name of argv[0].
+#[cfg(target_os = "linux")]
+fn name_running(name: &str) -> bool {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        let Some(pid) = entry.file_name().to_str().and_then(|pid| pid.parse().ok()) else {
+            return false;
+        };
+        let comm = fs::read_to_string(entry.path().join("comm")).unwrap_or_default();
+        let cmdline = fs::read(entry.path().join("cmdline")).unwrap_or_default();
+        let argv0 = cmdline.split(|b| *b == 0).next().unwrap_or_default();
+        let argv0 = String::from_utf8_lossy(argv0);
+        (comm.trim_end() == name || argv0.rsplit('/').next() == Some(name)) && pid_running(pid)
+    })
+}
+
+#[cfg(windows)]
+fn pid_running(pid: u32) -> bool {
+    use windows_sys::Win32::{
+        Foundation::{CloseHandle, STILL_ACTIVE},
+        System::Threading::{GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION},
+    };
+    let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid) };
+    if handle == 0 {
+        return false;
+    }
+    let mut code = 0;
+    let ok = unsafe { GetExitCodeProcess(handle, &mut code) } != 0;
+    unsafe { CloseHandle(handle) };
+    ok && code == STILL_ACTIVE as u32
+}
+
+// Matches the executable's file name, with or without .exe, ignoring case.
+#[cfg(windows)]
+fn name_running(name: &str) -> bool {
+    use windows_sys::Win32::{
+        Foundation::{CloseHandle, INVALID_HANDLE_VALUE},
+        System::Diagnostics::ToolHelp::{
+            CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W,
+            TH32CS_SNAPPROCESS,
+        },
+    };
+    let snapshot = unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0) };
+    if snapshot == INVALID_HANDLE_VALUE {
+        return false;
+    }
+    let mut entry: PROCESSENTRY32W = unsafe { std::mem::zeroed() };
+    entry.dwSize = std::mem::size_of::<PROCESSENTRY32W>() as u32;
+    let mut found = false;
+    let mut more = unsafe { Process32FirstW(snapshot, &mut entry) } != 0;
+    while more && !found {
+        let len = entry
+            .szExeFile
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(entry.szExeFile.len());
+        let exe = String::from_utf16_lossy(&entry.szExeFile[..len]);
+        found =
+            exe.eq_ignore_ascii_case(name) || exe.eq_ignore_ascii_case(&format!("{}.exe", name));
+        more = unsafe { Process32NextW(snapshot, &mut entry) } != 0;
+    }
+    unsafe { CloseHandle(snapshot) };
+    found
+}
+
+// validate reports process and pid_file as unsupported here
+#[cfg(not(any(target_os = "linux", windows)))]
+fn pid_running(_pid: u32) -> bool {
+    false
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn name_running(_name: &str) -> bool {
+    false
+}
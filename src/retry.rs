@@ -0,0 +1,37 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+// [retry]: how failed registrations are retried. The delay starts at initial_delay,
+// is multiplied after every further failure up to max_delay, then spread by ±jitter
+// so services that failed together don't all retry in the same second.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    // Seconds
+    pub initial_delay: f64,
+    pub max_delay: f64,
+    pub multiplier: f64,
+    // Fraction of the delay, 0.2 = ±20%
+    pub jitter: f64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            initial_delay: 1.0,
+            max_delay: 300.0,
+            multiplier: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl RetryConfig {
+    // Delay before the next attempt after `failures` failures in a row (at least 1).
+    pub fn delay(&self, failures: u32) -> Duration {
+        let exponent = failures.saturating_sub(1).min(64) as i32;
+        let base = (self.initial_delay * self.multiplier.powi(exponent)).min(self.max_delay);
+        let spread = 1.0 + self.jitter * (fastrand::f64() * 2.0 - 1.0);
+        Duration::from_secs_f64((base * spread).clamp(0.0, self.max_delay))
+    }
+}
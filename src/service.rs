@@ -0,0 +1,132 @@
+use crate::{
+    cli::Cli,
+    daemon::{Advertiser, Event, SHUTDOWN_PROGRESS_INTERVAL},
+    error::Result,
+    logging, settings,
+};
+use clap::Parser;
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::{mpsc, OnceLock},
+    time::Duration,
+};
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle},
+    service_dispatcher,
+};
+
+define_windows_service!(ffi_service_main, windns_sd_service_main);
+const SERVICE_NAME: &str = "windns-sd";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+// --config given on the service's command line (ImagePath)
+static CONFIG_PATH: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+pub fn start(config_path: Option<PathBuf>) -> windows_service::Result<()> {
+    CONFIG_PATH.get_or_init(|| config_path);
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+}
+
+// Start parameters (`sc start windns-sd --config D:\other.toml`) win over the command line.
+// The first argument is always the service name.
+fn config_path(arguments: Vec<OsString>) -> PathBuf {
+    let start_parameters = Cli::try_parse_from(arguments)
+        .ok()
+        .and_then(|cli| cli.config);
+    start_parameters
+        .or_else(|| CONFIG_PATH.get().cloned().flatten())
+        .unwrap_or_else(settings::default_config_path)
+}
+
+fn windns_sd_service_main(arguments: Vec<OsString>) {
+    let config_path = config_path(arguments);
+    logging::init(&config_path, false);
+    if let Err(e) = run_service(config_path) {
+        log::error!("windns-sd stopped: {}", e);
+    }
+}
+
+fn run_service(config_path: PathBuf) -> Result<()> {
+    // Create a channel to be able to poll a stop event from the service worker loop.
+    let (service_control_tx, service_control_rx) = mpsc::channel();
+    let events = service_control_tx.clone();
+    let event_handler = move |control_event| -> ServiceControlHandlerResult {
+        match control_event {
+            // Notifies a service to report its current status information to the service
+            // control manager. Always return NoError even if not implemented.
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            // Handle stop, and withdraw the services the same way when Windows shuts down
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                let _ = service_control_tx.send(Event::Stop);
+                ServiceControlHandlerResult::NoError
+            }
+            // `sc control windns-sd paramchange` re-reads the config file
+            ServiceControl::ParamChange => {
+                let _ = service_control_tx.send(Event::Reload);
+                ServiceControlHandlerResult::NoError
+            }
+            _ => ServiceControlHandlerResult::NotImplemented,
+        }
+    };
+    let status_handle = service_control_handler::register(SERVICE_NAME, event_handler)?;
+    let result = serve(&status_handle, &events, &service_control_rx, &config_path);
+    // Report why we stopped; ServiceSpecific codes come from Error::exit_code.
+    let exit_code = match &result {
+        Ok(()) => ServiceExitCode::Win32(0),
+        Err(e) => ServiceExitCode::ServiceSpecific(e.exit_code()),
+    };
+    status_handle.set_service_status(ServiceStatus {
+        service_type: SERVICE_TYPE,
+        current_state: ServiceState::Stopped,
+        controls_accepted: ServiceControlAccept::empty(),
+        exit_code,
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    })?;
+    result
+}
+
+fn serve(
+    status_handle: &ServiceStatusHandle,
+    events: &mpsc::Sender<Event>,
+    service_control_rx: &mpsc::Receiver<Event>,
+    config_path: &Path,
+) -> Result<()> {
+    // Tell the system that service is running
+    status_handle.set_service_status(ServiceStatus {
+        service_type: SERVICE_TYPE,
+        current_state: ServiceState::Running,
+        controls_accepted: ServiceControlAccept::STOP
+            | ServiceControlAccept::SHUTDOWN
+            | ServiceControlAccept::PARAM_CHANGE,
+        exit_code: ServiceExitCode::Win32(0),
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    })?;
+    // Start the service worker loop
+    let mut advertiser = Advertiser::start(config_path, events)?;
+    advertiser.run(service_control_rx);
+    // Bump the checkpoint on every progress report so the SCM sees the stop moving
+    let mut checkpoint = 0;
+    advertiser.shutdown(|_| {
+        checkpoint += 1;
+        let _ = status_handle.set_service_status(ServiceStatus {
+            service_type: SERVICE_TYPE,
+            current_state: ServiceState::StopPending,
+            controls_accepted: ServiceControlAccept::empty(),
+            exit_code: ServiceExitCode::Win32(0),
+            checkpoint,
+            wait_hint: SHUTDOWN_PROGRESS_INTERVAL * 2,
+            process_id: None,
+        });
+    });
+    Ok(())
+}
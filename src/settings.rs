@@ -0,0 +1,596 @@
+use crate::{
+    api::ApiConfig,
+    backend::{mdns::MdnsConfig, BackendKind},
+    conflict::ConflictStrategy,
+    error::{Error, Result},
+    health::HealthConfig,
+    ipc::IpcConfig,
+    logging::LoggingConfig,
+    metrics::MetricsConfig,
+    retry::RetryConfig,
+    template,
+    validate::{self, Diagnostic},
+};
+use config::{Config, ConfigError, File, Map, Value, ValueKind};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct ServiceConfig {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub service_type: String,
+    pub port: u16,
+    // Advertised unless set to false
+    pub enabled: Option<bool>,
+    // Entries sharing a group can be switched off and on together at runtime
+    pub group: Option<String>,
+    // What to do when another host advertises the same name, for backends that report it
+    #[serde(default)]
+    pub on_conflict: ConflictStrategy,
+    // Where port = 0 picks from, inclusive
+    pub port_range: Option<(u16, u16)>,
+    // Builtin backend only: advertise only on the interface with this IPv4 address, and
+    // give the records this TTL in seconds
+    pub interface: Option<Ipv4Addr>,
+    pub ttl: Option<u32>,
+    // More TXT values, one key=value per line, read from a file whenever it changes or
+    // from a command run every text_interval seconds. They override `text`. A relative
+    // text_file is taken from the config file's directory.
+    pub text_file: Option<PathBuf>,
+    pub text_command: Option<String>,
+    pub text_interval: Option<f64>,
+    // Only advertise while a process with this name, or the one in this pid file, runs.
+    // A relative pid_file is taken from the config file's directory.
+    pub process: Option<String>,
+    pub pid_file: Option<PathBuf>,
+    // Hand the advertised port to the application: written to this file, and set as
+    // PORT for this command, which windns-sd runs (program first, no shell) and restarts.
+    // A relative port_file is taken from the config file's directory, where the command
+    // runs too.
+    pub port_file: Option<PathBuf>,
+    pub exec: Option<Vec<String>>,
+    // Tables last: TOML can't have plain values after them
+    pub text: Option<std::collections::HashMap<String, String>>,
+    // Only advertise while this check passes
+    pub health: Option<HealthConfig>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Settings {
+    #[serde(default)]
+    pub backend: BackendKind,
+    // Where ports picked for port = 0 and names picked after a conflict are remembered,
+    // windns-sd.state next to this file by default
+    #[serde(default)]
+    pub state_file: Option<PathBuf>,
+    #[serde(default)]
+    pub mdns: MdnsConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub api: ApiConfig,
+    #[serde(default)]
+    pub ipc: IpcConfig,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    pub services: std::collections::BTreeMap<String, ServiceConfig>,
+    // The file each entry came from, by key
+    #[serde(skip)]
+    pub files: BTreeMap<String, PathBuf>,
+}
+
+impl Settings {
+    // Parsed together with the drop-ins in config.d, with [defaults] and `extends`
+    // applied to the services and the placeholders in service names and TXT values
+    // replaced.
+    pub fn from_file(config_path: &Path) -> Result<Self> {
+        let config_error = |source: ConfigError| Error::Config {
+            path: config_path.to_path_buf(),
+            source: Box::new(source),
+        };
+        let invalid = |diagnostics: Vec<Diagnostic>| Error::Invalid {
+            path: config_path.to_path_buf(),
+            diagnostics,
+        };
+        // config.toml first, then the drop-ins; each is parsed on its own so errors name
+        // the file they're in, and a key may only come back in a later file to replace
+        // the earlier entry
+        let paths = Self::files(config_path)?;
+        let mut configs: Vec<Config> = Vec::new();
+        let mut origins: BTreeMap<String, usize> = BTreeMap::new();
+        let mut duplicates = Vec::new();
+        for (index, path) in paths.iter().enumerate() {
+            let config = Config::builder()
+                .add_source(File::from(path.as_path()))
+                .build()
+                .map_err(|source| Error::Config {
+                    path: path.clone(),
+                    source: Box::new(source),
+                })?;
+            let mut services: Vec<(String, Value)> = config
+                .get_table("services")
+                .unwrap_or_default()
+                .into_iter()
+                .collect();
+            services.sort_by(|a, b| a.0.cmp(&b.0));
+            for (key, value) in services {
+                let Some(earlier) = origins.insert(key.clone(), index) else {
+                    continue;
+                };
+                let overrides = value
+                    .into_table()
+                    .ok()
+                    .and_then(|table| table.get("override").cloned())
+                    .and_then(|value| value.into_bool().ok())
+                    .unwrap_or(false);
+                if overrides {
+                    // Merging would keep whatever the later entry leaves out
+                    remove_service(&mut configs[earlier], &key);
+                } else {
+                    duplicates.push(Diagnostic::error(
+                        format!("services.{}", key),
+                        format!(
+                            "defined in both {} and {}; set override = true in the later one to replace it",
+                            paths[earlier].display(),
+                            path.display()
+                        ),
+                    ));
+                }
+            }
+            configs.push(config);
+        }
+        if !duplicates.is_empty() {
+            return Err(invalid(duplicates));
+        }
+        let config = configs
+            .into_iter()
+            .fold(Config::builder(), |builder, config| {
+                builder.add_source(config)
+            })
+            .build()
+            .map_err(config_error)?;
+        let services = inherit(&config).map_err(invalid)?;
+        let mut builder = Config::builder().add_source(config);
+        if !services.is_empty() {
+            builder = builder
+                .set_override("services", services)
+                .map_err(config_error)?;
+        }
+        let mut settings: Settings = builder
+            .build()
+            .and_then(Config::try_deserialize)
+            .map_err(config_error)?;
+        let diagnostics = settings.expand();
+        if !diagnostics.is_empty() {
+            return Err(invalid(diagnostics));
+        }
+        settings.anchor(config_path);
+        settings.files = origins
+            .into_iter()
+            .map(|(key, index)| (key, paths[index].clone()))
+            .collect();
+        Ok(settings)
+    }
+
+    // The config file and the *.toml files in config.d next to it, in the order they
+    // apply: later files win.
+    pub fn files(config_path: &Path) -> Result<Vec<PathBuf>> {
+        let dir = config_dir(config_path).join("config.d");
+        let mut drop_ins: Vec<PathBuf> = match fs::read_dir(&dir) {
+            Ok(entries) => entries
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|e| e == "toml") && path.is_file())
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                return Err(Error::Config {
+                    path: dir,
+                    source: Box::new(ConfigError::Foreign(Box::new(e))),
+                })
+            }
+        };
+        drop_ins.sort();
+        drop_ins.insert(0, config_path.to_path_buf());
+        Ok(drop_ins)
+    }
+
+    // Make relative file paths relative to the config file's directory rather than the
+    // working directory, which is System32 for a Windows service.
+    fn anchor(&mut self, config_path: &Path) {
+        let dir = config_dir(config_path);
+        for service_config in self.services.values_mut() {
+            for path in [
+                &mut service_config.text_file,
+                &mut service_config.pid_file,
+                &mut service_config.port_file,
+            ]
+            .into_iter()
+            .flatten()
+            {
+                if path.is_relative() {
+                    *path = dir.join(&*path);
+                }
+            }
+        }
+    }
+
+    fn expand(&mut self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        for (key, service_config) in &mut self.services {
+            let mut expand = |field: String, value: &mut String| match template::expand(value) {
+                Ok(expanded) => *value = expanded,
+                Err(message) => diagnostics.push(Diagnostic::error(field, message)),
+            };
+            expand(format!("services.{}.name", key), &mut service_config.name);
+            for (txt_key, value) in service_config.text.iter_mut().flatten() {
+                expand(format!("services.{}.text.{}", key, txt_key), value);
+            }
+        }
+        diagnostics
+    }
+
+    // from_file followed by validation. Warnings are printed, errors refuse the whole file.
+    pub fn load(config_path: &Path) -> Result<Self> {
+        let settings = Self::from_file(config_path)?;
+        let diagnostics = validate::validate(&settings);
+        if validate::has_errors(&diagnostics) {
+            return Err(Error::Invalid {
+                path: config_path.to_path_buf(),
+                diagnostics,
+            });
+        }
+        for diagnostic in &diagnostics {
+            log::warn!("{}", diagnostic);
+        }
+        Ok(settings)
+    }
+}
+
+// Drop [services.<key>] from one file's tables.
+fn remove_service(config: &mut Config, key: &str) {
+    if let ValueKind::Table(root) = &mut config.cache.kind {
+        if let Some(Value {
+            kind: ValueKind::Table(services),
+            ..
+        }) = root.get_mut("services")
+        {
+            services.remove(key);
+        }
+    }
+}
+
+// The directory the config file is in, where the state, log, socket and config.d go
+// by default.
+pub fn config_dir(config_path: &Path) -> &Path {
+    config_path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+}
+
+// Apply [defaults] and `extends` to the raw [services.*] tables. An entry starts from
+// the entry it extends, which is resolved the same way, or else from [defaults]; its own
+// values win. `text` tables are merged key by key, any other value replaces the
+// inherited one as a whole.
+fn inherit(config: &Config) -> std::result::Result<Map<String, Value>, Vec<Diagnostic>> {
+    // Not a table: left for deserializing to report
+    let Ok(services) = config.get_table("services") else {
+        return Ok(Map::new());
+    };
+    let defaults = match config.get_table("defaults") {
+        Ok(defaults) if defaults.contains_key("extends") => {
+            return Err(vec![Diagnostic::error(
+                "defaults.extends".into(),
+                "[defaults] can't extend an entry".into(),
+            )])
+        }
+        Ok(defaults) => defaults,
+        Err(ConfigError::NotFound(_)) => Map::new(),
+        Err(_) => {
+            return Err(vec![Diagnostic::error(
+                "defaults".into(),
+                "must be a table".into(),
+            )])
+        }
+    };
+    let mut keys: Vec<&String> = services.keys().collect();
+    keys.sort();
+    let mut resolved = BTreeMap::new();
+    let mut diagnostics = Vec::new();
+    for key in keys {
+        let mut chain = Vec::new();
+        if let Err(message) = resolve(key, &services, &defaults, &mut resolved, &mut chain) {
+            diagnostics.push(Diagnostic::error(
+                format!("services.{}.extends", key),
+                message,
+            ));
+        }
+    }
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+    Ok(resolved
+        .into_iter()
+        .map(|(key, table)| (key, Value::from(table)))
+        .collect())
+}
+
+// One entry with everything it inherits. `chain` holds the entries whose `extends` led
+// here, to catch loops.
+fn resolve(
+    key: &str,
+    services: &Map<String, Value>,
+    defaults: &Map<String, Value>,
+    resolved: &mut BTreeMap<String, Map<String, Value>>,
+    chain: &mut Vec<String>,
+) -> std::result::Result<Map<String, Value>, String> {
+    if let Some(table) = resolved.get(key) {
+        return Ok(table.clone());
+    }
+    let Ok(own) = services[key].clone().into_table() else {
+        return Ok(Map::new());
+    };
+    let inherited = match own.get("extends") {
+        None => defaults.clone(),
+        Some(parent) => {
+            let parent = parent
+                .clone()
+                .into_string()
+                .map_err(|_| "must be the key of another entry".to_string())?;
+            chain.push(key.to_string());
+            if let Some(start) = chain.iter().position(|k| *k == parent) {
+                return Err(format!(
+                    "entries extend each other in a loop: {} -> {}",
+                    chain[start..].join(" -> "),
+                    parent
+                ));
+            }
+            if !services.contains_key(&parent) {
+                return Err(format!("there is no services.{} to extend", parent));
+            }
+            let inherited = resolve(&parent, services, defaults, resolved, chain)?;
+            chain.pop();
+            inherited
+        }
+    };
+    let mut table = inherited;
+    for (field, mut value) in own {
+        if field == "text" {
+            if let (Some(ValueKind::Table(inherited_text)), ValueKind::Table(text)) =
+                (table.remove("text").map(|v| v.kind), &mut value.kind)
+            {
+                for (txt_key, txt_value) in inherited_text {
+                    text.entry(txt_key).or_insert(txt_value);
+                }
+            }
+        }
+        table.insert(field, value);
+    }
+    resolved.insert(key.to_string(), table.clone());
+    Ok(table)
+}
+
+// $ProgramData/windns-sd/config.toml on Windows, /etc/windns-sd/config.toml elsewhere
+pub fn default_config_path() -> PathBuf {
+    if cfg!(windows) {
+        let program_data = env::var_os("ProgramData").unwrap_or_else(|| "C:\\ProgramData".into());
+        Path::new(&program_data)
+            .join("windns-sd")
+            .join("config.toml")
+    } else {
+        Path::new("/etc/windns-sd").join("config.toml")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    // Settings::from_file on `contents` as config.toml in a directory of its own.
+    fn load(name: &str, contents: &str) -> Result<Settings> {
+        let dir = env::temp_dir().join(format!("windns-sd-{}-{}", process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(&path, contents).unwrap();
+        let settings = Settings::from_file(&path);
+        fs::remove_dir_all(&dir).unwrap();
+        settings
+    }
+
+    #[test]
+    fn print_config_with_every_field() {
+        let settings = load(
+            "print",
+            r#"
+            [services.web]
+            name = "Web"
+            type = "_http._tcp"
+            port = 0
+            enabled = true
+            group = "apps"
+            on_conflict = "hostname"
+            port_range = [8000, 8100]
+            interface = "127.0.0.1"
+            ttl = 120
+            text = { path = "/" }
+            text_file = "web.txt"
+            text_command = "echo version=1"
+            text_interval = 30
+            process = "web"
+            pid_file = "/run/web.pid"
+            port_file = "/run/web.port"
+            exec = ["web", "--port-from-env"]
+            health = { http = "http://127.0.0.1/", status = 204 }
+            "#,
+        )
+        .unwrap();
+        // What `windns-sd print-config` prints, read back
+        let printed = toml::to_string_pretty(&settings).unwrap();
+        let reread = load("print-reread", &printed).unwrap();
+        assert_eq!(reread.services, settings.services);
+    }
+
+    #[test]
+    fn relative_paths_are_in_the_config_dir() {
+        let absolute = env::temp_dir().join("api.txt");
+        let settings = load(
+            "relative",
+            &format!(
+                r#"
+                [services.web]
+                name = "Web"
+                type = "_http._tcp"
+                port = 80
+                text_file = "web.txt"
+                pid_file = "run/web.pid"
+                port_file = "web.port"
+                [services.api]
+                name = "API"
+                type = "_http._tcp"
+                port = 81
+                text_file = '{}'
+                "#,
+                absolute.display()
+            ),
+        )
+        .unwrap();
+        let dir = env::temp_dir().join(format!("windns-sd-{}-relative", process::id()));
+        let web = &settings.services["web"];
+        assert_eq!(web.text_file, Some(dir.join("web.txt")));
+        assert_eq!(web.pid_file, Some(dir.join("run/web.pid")));
+        assert_eq!(web.port_file, Some(dir.join("web.port")));
+        let api = &settings.services["api"];
+        assert_eq!(api.text_file, Some(absolute));
+    }
+
+    // The diagnostics of a file refused as invalid.
+    fn errors(result: Result<Settings>) -> Vec<String> {
+        match result {
+            Err(Error::Invalid { diagnostics, .. }) => {
+                diagnostics.iter().map(|d| d.to_string()).collect()
+            }
+            Err(e) => panic!("expected invalid, got {}", e),
+            Ok(_) => panic!("expected invalid, got settings"),
+        }
+    }
+
+    #[test]
+    fn defaults_then_parent_then_own() {
+        let settings = load(
+            "inherit",
+            r#"
+            [defaults]
+            name = "Default"
+            group = "all"
+            port = 1
+            [services.base]
+            name = "Base"
+            type = "_http._tcp"
+            port = 2
+            [services.child]
+            extends = "base"
+            name = "Child"
+            [services.plain]
+            type = "_ssh._tcp"
+            "#,
+        )
+        .unwrap();
+        let child = &settings.services["child"];
+        assert_eq!(
+            (child.name.as_str(), child.service_type.as_str(), child.port),
+            ("Child", "_http._tcp", 2)
+        );
+        assert_eq!(child.group.as_deref(), Some("all"));
+        let plain = &settings.services["plain"];
+        assert_eq!((plain.name.as_str(), plain.port), ("Default", 1));
+    }
+
+    #[test]
+    fn text_merged_by_key() {
+        let settings = load(
+            "inherit-text",
+            r#"
+            [defaults]
+            type = "_http._tcp"
+            port = 80
+            text = { a = "default", b = "default" }
+            [services.base]
+            name = "Base"
+            text = { b = "base", c = "base" }
+            [services.child]
+            extends = "base"
+            name = "Child"
+            text = { c = "child" }
+            "#,
+        )
+        .unwrap();
+        let text = settings.services["child"].text.clone().unwrap();
+        let mut text: Vec<(String, String)> = text.into_iter().collect();
+        text.sort();
+        let expected = [("a", "default"), ("b", "base"), ("c", "child")]
+            .map(|(k, v)| (k.to_string(), v.to_string()));
+        assert_eq!(text, expected);
+    }
+
+    #[test]
+    fn extends_loop() {
+        let result = load(
+            "inherit-loop",
+            r#"
+            [services.a]
+            extends = "b"
+            [services.b]
+            extends = "a"
+            "#,
+        );
+        assert_eq!(
+            errors(result),
+            [
+                "error: services.a.extends: entries extend each other in a loop: a -> b -> a",
+                "error: services.b.extends: entries extend each other in a loop: b -> a -> b",
+            ]
+        );
+    }
+
+    #[test]
+    fn extends_missing_entry() {
+        let result = load(
+            "inherit-missing",
+            r#"
+            [services.a]
+            extends = "nope"
+            "#,
+        );
+        assert_eq!(
+            errors(result),
+            ["error: services.a.extends: there is no services.nope to extend"]
+        );
+    }
+
+    #[test]
+    fn defaults_cannot_extend() {
+        let result = load(
+            "inherit-defaults",
+            r#"
+            [defaults]
+            extends = "a"
+            [services.a]
+            name = "A"
+            "#,
+        );
+        assert_eq!(
+            errors(result),
+            ["error: defaults.extends: [defaults] can't extend an entry"]
+        );
+    }
+}
@@ -0,0 +1,59 @@
+// What windns-sd remembers across restarts, as JSON in windns-sd.state next to the
+// config file (or `state_file`).
+use crate::settings;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct State {
+    // Ports allocated to port = 0 entries, by key
+    #[serde(default)]
+    pub ports: BTreeMap<String, u16>,
+    // Names picked after a name conflict, by key
+    #[serde(default)]
+    pub names: BTreeMap<String, Renamed>,
+}
+
+// The name is only used while the entry is still configured with the name it replaced.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Renamed {
+    pub configured: String,
+    pub name: String,
+}
+
+impl State {
+    // A missing file is a first start; an unreadable one is reported and started over.
+    pub fn load(path: &Path) -> Self {
+        match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                log::warn!(path:% = path.display(), error:% = e; "Ignoring unreadable state file");
+                State::default()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => State::default(),
+            Err(e) => {
+                log::warn!(path:% = path.display(), error:% = e; "Can't read state file");
+                State::default()
+            }
+        }
+    }
+
+    // Written to a temporary file first so a crash never leaves half a file behind.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let contents = serde_json::to_string_pretty(self).map_err(io::Error::other)?;
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        fs::write(&temporary, contents)?;
+        fs::rename(&temporary, path)
+    }
+}
+
+pub fn path(state_file: Option<&Path>, config_path: &Path) -> PathBuf {
+    match state_file {
+        Some(path) => path.to_path_buf(),
+        None => settings::config_dir(config_path).join("windns-sd.state"),
+    }
+}
@@ -0,0 +1,46 @@
+// Placeholders in service names and TXT values, replaced when the config is loaded so
+// one file serves every machine: {hostname}, {computername}, {os}, {env:VAR} and
+// {mac:INTERFACE}. {{ and }} stand for literal braces.
+use crate::host;
+use std::env;
+
+pub fn expand(value: &str) -> Result<String, String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find(['{', '}']) {
+        expanded.push_str(&rest[..start]);
+        let brace = &rest[start..];
+        if let Some(after) = brace
+            .strip_prefix("{{")
+            .or_else(|| brace.strip_prefix("}}"))
+        {
+            expanded.push_str(&brace[..1]);
+            rest = after;
+        } else if let Some(after) = brace.strip_prefix('}') {
+            expanded.push('}');
+            rest = after;
+        } else {
+            let Some((name, after)) = brace[1..].split_once('}') else {
+                return Err(format!("\"{}\" has a {{ without a matching }}", value));
+            };
+            expanded.push_str(&placeholder(name)?);
+            rest = after;
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn placeholder(name: &str) -> Result<String, String> {
+    match name.split_once(':') {
+        None if name == "hostname" => Ok(host::hostname()),
+        None if name == "computername" => Ok(host::computer_name()),
+        None if name == "os" => Ok(env::consts::OS.to_string()),
+        Some(("env", variable)) => {
+            env::var(variable).map_err(|_| format!("environment variable {} is not set", variable))
+        }
+        Some(("mac", interface)) => host::mac_address(interface)
+            .ok_or_else(|| format!("no network interface {} with a hardware address", interface)),
+        _ => Err(format!("unknown placeholder {{{}}}", name)),
+    }
+}
@@ -0,0 +1,179 @@
+// TXT values a [services.*] entry reads from its text_file or text_command, laid over
+// its static `text` and pushed to the live registration whenever they change.
+use crate::{
+    command,
+    daemon::Event,
+    settings::ServiceConfig,
+    validate::{self, Severity},
+};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+// Seconds between two runs of text_command when text_interval is not set.
+pub const DEFAULT_INTERVAL: f64 = 30.0;
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type Text = HashMap<String, String>;
+
+// One key=value per line. Blank lines and lines starting with # are skipped; a line
+// without = is a key with an empty value.
+pub fn parse(contents: &str) -> Text {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| match line.split_once('=') {
+            Some((key, value)) => (key.trim().to_string(), value.trim().to_string()),
+            None => (line.to_string(), String::new()),
+        })
+        .collect()
+}
+
+// Where one entry's file and command values stand.
+pub struct DynamicText {
+    file_path: Option<PathBuf>,
+    command: Option<String>,
+    interval: Duration,
+    file: Text,
+    modified: Option<SystemTime>,
+    output: Text,
+    // When the command runs next; None while it is running
+    next_run: Option<Instant>,
+}
+
+impl DynamicText {
+    // None for an entry without text_file or text_command. The file is read right away,
+    // the command runs on the next tick.
+    pub fn new(key: &str, config: &ServiceConfig) -> Option<Self> {
+        if config.text_file.is_none() && config.text_command.is_none() {
+            return None;
+        }
+        let mut dynamic = DynamicText {
+            file_path: config.text_file.clone(),
+            command: config.text_command.clone(),
+            interval: interval(config),
+            file: Text::new(),
+            modified: None,
+            output: Text::new(),
+            next_run: Some(Instant::now()),
+        };
+        dynamic.poll_file(key);
+        Some(dynamic)
+    }
+
+    pub fn matches(&self, config: &ServiceConfig) -> bool {
+        self.file_path == config.text_file
+            && self.command == config.text_command
+            && self.interval == interval(config)
+    }
+
+    // Re-read the file if its modification time moved. Returns whether the values changed.
+    pub fn poll_file(&mut self, key: &str) -> bool {
+        let Some(path) = &self.file_path else {
+            return false;
+        };
+        let modified = modified(path);
+        if modified == self.modified {
+            return false;
+        }
+        self.modified = modified;
+        match fs::read_to_string(path) {
+            Ok(contents) => match checked(key, "text_file", parse(&contents)) {
+                Some(file) if file != self.file => {
+                    self.file = file;
+                    true
+                }
+                _ => false,
+            },
+            // Keep the last values; the next change of the file tries again
+            Err(e) => {
+                log::warn!(
+                    service = key,
+                    path:% = path.display(),
+                    error:% = e;
+                    "Can't read text_file"
+                );
+                false
+            }
+        }
+    }
+
+    // Start the command on its own thread if it is due; its output comes back as
+    // Event::Text.
+    pub fn run_if_due(&mut self, key: &str, events: &mpsc::Sender<Event>) {
+        let Some(command) = &self.command else {
+            return;
+        };
+        if self.next_run.is_none_or(|at| at > Instant::now()) {
+            return;
+        }
+        self.next_run = None;
+        let (key, command, events) = (key.to_string(), command.clone(), events.clone());
+        thread::spawn(move || {
+            let _ = events.send(Event::Text(key, run(&command)));
+        });
+    }
+
+    // Take a finished run's output and schedule the next one. Returns whether the values
+    // changed; a failed run keeps the last ones.
+    pub fn finished(&mut self, key: &str, result: Result<Text, String>) -> bool {
+        self.next_run = Some(Instant::now() + self.interval);
+        match result {
+            Ok(output) => match checked(key, "text_command", output) {
+                Some(output) if output != self.output => {
+                    self.output = output;
+                    true
+                }
+                _ => false,
+            },
+            Err(e) => {
+                log::warn!(service = key, error = e.as_str(); "text_command failed");
+                false
+            }
+        }
+    }
+
+    // The static text with the file's values and then the command's laid over it.
+    pub fn merge(&self, text: Option<&Text>) -> Text {
+        let mut text = text.cloned().unwrap_or_default();
+        text.extend(self.file.clone());
+        text.extend(self.output.clone());
+        text
+    }
+}
+
+// Values that would make an invalid TXT record are dropped as a whole.
+fn checked(key: &str, source: &str, text: Text) -> Option<Text> {
+    let mut diagnostics = Vec::new();
+    validate::check_text(&format!("services.{}", key), &text, &mut diagnostics);
+    if !validate::has_errors(&diagnostics) {
+        return Some(text);
+    }
+    for diagnostic in diagnostics.iter().filter(|d| d.severity == Severity::Error) {
+        log::warn!(source; "Ignoring new TXT values: {}", diagnostic);
+    }
+    None
+}
+
+fn interval(config: &ServiceConfig) -> Duration {
+    Duration::try_from_secs_f64(config.text_interval.unwrap_or(DEFAULT_INTERVAL))
+        .unwrap_or(Duration::from_secs_f64(DEFAULT_INTERVAL))
+}
+
+fn run(command: &str) -> Result<Text, String> {
+    let output = command::output(command, COMMAND_TIMEOUT).map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(command::failure(&output));
+    }
+    Ok(parse(&String::from_utf8_lossy(&output.stdout)))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
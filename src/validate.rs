@@ -0,0 +1,562 @@
+use crate::{
+    backend::BackendKind,
+    health::{self, HealthConfig},
+    retry::RetryConfig,
+    settings::{ServiceConfig, Settings},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+// RFC 6763 §4.1.1 / §6, RFC 6335 §5.1
+pub const MAX_LABEL_LEN: usize = 63;
+const MAX_SERVICE_NAME_LEN: usize = 15;
+const MAX_TXT_STRING_LEN: usize = 255;
+const RECOMMENDED_TXT_KEY_LEN: usize = 9;
+// RFC 6763 §6.2: larger TXT records no longer fit a typical Ethernet packet
+const RECOMMENDED_TXT_RECORD_LEN: usize = 1300;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    // Dotted TOML path the problem came from, e.g. `services.smb.type`
+    pub key: String,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn error(key: String, message: String) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            key,
+            message,
+        }
+    }
+
+    fn warning(key: String, message: String) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            key,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}: {}", severity, self.key, self.message)
+    }
+}
+
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(|d| d.severity == Severity::Error)
+}
+
+// Check everything the backends would otherwise reject (or silently mangle) at
+// registration time, so a bad entry is reported before anything is advertised.
+pub fn validate(settings: &Settings) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut seen: BTreeMap<(String, String), &str> = BTreeMap::new();
+    let mut ports: BTreeMap<(u16, &str), &str> = BTreeMap::new();
+    check_retry(&settings.retry, &mut diagnostics);
+    for (key, service_config) in &settings.services {
+        let table = format!("services.{}", key);
+        diagnostics.extend(validate_service(key, service_config));
+        if settings.backend != BackendKind::Builtin
+            && (service_config.interface.is_some() || service_config.ttl.is_some())
+        {
+            diagnostics.push(Diagnostic::warning(
+                table.clone(),
+                "interface and ttl are only used by the builtin backend".into(),
+            ));
+        }
+        // The responder only joins the group on [mdns] interface when that is set
+        if let (Some(responder), Some(interface)) =
+            (settings.mdns.interface, service_config.interface)
+        {
+            if interface != responder {
+                diagnostics.push(Diagnostic::error(
+                    format!("{}.interface", table),
+                    format!("must be {}, the [mdns] interface, or left out", responder),
+                ));
+            }
+        }
+        // port = 0 is allocated at startup and never collides here
+        if service_config.port != 0 {
+            let protocol = service_config.service_type.rsplit('.').next().unwrap_or("");
+            let port = (service_config.port, protocol);
+            match ports.get(&port) {
+                Some(first) => diagnostics.push(Diagnostic::warning(
+                    format!("{}.port", table),
+                    format!(
+                        "port {} is also used by services.{}",
+                        service_config.port, first
+                    ),
+                )),
+                None => {
+                    ports.insert(port, key);
+                }
+            }
+        }
+        // DNS names compare case-insensitively
+        let identity = (
+            service_config.name.to_lowercase(),
+            service_config.service_type.to_lowercase(),
+        );
+        match seen.get(&identity) {
+            Some(first) => diagnostics.push(Diagnostic::error(
+                table,
+                format!(
+                    "\"{}\" of type {} is already advertised by services.{}",
+                    service_config.name, service_config.service_type, first
+                ),
+            )),
+            None => {
+                seen.insert(identity, key);
+            }
+        }
+    }
+    diagnostics
+}
+
+// The checks that only need the entry itself, for services added at runtime.
+pub fn validate_service(key: &str, service_config: &ServiceConfig) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let table = format!("services.{}", key);
+    check_service_type(&table, &service_config.service_type, &mut diagnostics);
+    check_instance_name(&table, &service_config.name, &mut diagnostics);
+    if service_config.group.as_deref() == Some("") {
+        diagnostics.push(Diagnostic::error(
+            format!("{}.group", table),
+            "group name is empty".into(),
+        ));
+    }
+    if let Some(text) = &service_config.text {
+        check_text(&table, text, &mut diagnostics);
+    }
+    if let Some((low, high)) = service_config.port_range {
+        let key = format!("{}.port_range", table);
+        if low == 0 || low > high {
+            diagnostics.push(Diagnostic::error(
+                key,
+                format!(
+                    "[{}, {}] must be a range of ports from low to high",
+                    low, high
+                ),
+            ));
+        } else if service_config.port != 0 {
+            diagnostics.push(Diagnostic::warning(
+                key,
+                "has no effect unless port = 0".into(),
+            ));
+        }
+    }
+    if service_config.ttl == Some(0) {
+        diagnostics.push(Diagnostic::error(
+            format!("{}.ttl", table),
+            "must be at least 1 second, 0 withdraws the records".into(),
+        ));
+    }
+    if let Some(interval) = service_config.text_interval {
+        let key = format!("{}.text_interval", table);
+        if !(interval.is_finite() && interval > 0.0) {
+            diagnostics.push(Diagnostic::error(
+                key,
+                "must be a positive number of seconds".into(),
+            ));
+        } else if service_config.text_command.is_none() {
+            diagnostics.push(Diagnostic::warning(
+                key,
+                "has no effect without text_command".into(),
+            ));
+        }
+    }
+    if let Some(health) = &service_config.health {
+        check_health(&format!("{}.health", table), health, &mut diagnostics);
+    }
+    if service_config.process.as_deref() == Some("") {
+        diagnostics.push(Diagnostic::error(
+            format!("{}.process", table),
+            "process name is empty".into(),
+        ));
+    }
+    if service_config
+        .exec
+        .as_ref()
+        .is_some_and(|argv| argv.first().is_none_or(|program| program.is_empty()))
+    {
+        diagnostics.push(Diagnostic::error(
+            format!("{}.exec", table),
+            "must start with the program to run".into(),
+        ));
+    }
+    if !cfg!(any(target_os = "linux", windows))
+        && (service_config.process.is_some() || service_config.pid_file.is_some())
+    {
+        diagnostics.push(Diagnostic::error(
+            table.clone(),
+            "process and pid_file are only supported on Linux and Windows".into(),
+        ));
+    }
+    diagnostics
+}
+
+pub fn check_text(table: &str, text: &HashMap<String, String>, diagnostics: &mut Vec<Diagnostic>) {
+    let mut text_keys: Vec<_> = text.iter().collect();
+    text_keys.sort();
+    for (txt_key, value) in text_keys {
+        check_txt_entry(table, txt_key, value, diagnostics);
+    }
+    let len: usize = text.iter().map(|(k, v)| 1 + k.len() + 1 + v.len()).sum();
+    if len > RECOMMENDED_TXT_RECORD_LEN {
+        diagnostics.push(Diagnostic::warning(
+            format!("{}.text", table),
+            format!(
+                "TXT record is {} bytes, more than the recommended {}",
+                len, RECOMMENDED_TXT_RECORD_LEN
+            ),
+        ));
+    }
+}
+
+fn check_retry(retry: &RetryConfig, diagnostics: &mut Vec<Diagnostic>) {
+    let mut check = |field: &str, ok: bool, requirement: &str| {
+        if !ok {
+            diagnostics.push(Diagnostic::error(
+                format!("retry.{}", field),
+                requirement.to_string(),
+            ));
+        }
+    };
+    check(
+        "initial_delay",
+        retry.initial_delay.is_finite() && retry.initial_delay > 0.0,
+        "must be a positive number of seconds",
+    );
+    check(
+        "max_delay",
+        retry.max_delay.is_finite() && retry.max_delay >= retry.initial_delay,
+        "must be at least initial_delay",
+    );
+    check(
+        "multiplier",
+        retry.multiplier.is_finite() && retry.multiplier >= 1.0,
+        "must be 1 or more",
+    );
+    check(
+        "jitter",
+        (0.0..=1.0).contains(&retry.jitter),
+        "must be between 0 and 1",
+    );
+}
+
+fn check_health(table: &str, health: &HealthConfig, diagnostics: &mut Vec<Diagnostic>) {
+    let checks = [
+        health.tcp.is_some(),
+        health.http.is_some(),
+        health.command.is_some(),
+    ];
+    if checks.iter().filter(|set| **set).count() != 1 {
+        diagnostics.push(Diagnostic::error(
+            table.to_string(),
+            "set exactly one of tcp, http or command".into(),
+        ));
+    }
+    if let Some(address) = health.tcp.as_deref().filter(|a| !health::has_port(a)) {
+        diagnostics.push(Diagnostic::error(
+            format!("{}.tcp", table),
+            format!("\"{}\" must have the form host:port", address),
+        ));
+    }
+    if let Some(url) = health.http.as_deref().filter(|u| !u.starts_with("http://")) {
+        diagnostics.push(Diagnostic::error(
+            format!("{}.http", table),
+            format!("\"{}\" must be an http:// URL", url),
+        ));
+    }
+    for (field, seconds) in [("interval", health.interval), ("timeout", health.timeout)] {
+        if !(seconds.is_finite() && seconds > 0.0) {
+            diagnostics.push(Diagnostic::error(
+                format!("{}.{}", table, field),
+                "must be a positive number of seconds".into(),
+            ));
+        }
+    }
+    if health.timeout > health.interval {
+        diagnostics.push(Diagnostic::warning(
+            format!("{}.timeout", table),
+            "is longer than interval".into(),
+        ));
+    }
+    for (field, threshold) in [
+        ("healthy_threshold", health.healthy_threshold),
+        ("unhealthy_threshold", health.unhealthy_threshold),
+    ] {
+        if threshold == 0 {
+            diagnostics.push(Diagnostic::error(
+                format!("{}.{}", table, field),
+                "must be 1 or more".into(),
+            ));
+        }
+    }
+}
+
+fn check_service_type(table: &str, service_type: &str, diagnostics: &mut Vec<Diagnostic>) {
+    let key = format!("{}.type", table);
+    let labels: Vec<&str> = service_type.trim_end_matches('.').split('.').collect();
+    let [service, protocol] = labels[..] else {
+        diagnostics.push(Diagnostic::error(
+            key,
+            format!(
+                "\"{}\" must have the form _name._tcp or _name._udp",
+                service_type
+            ),
+        ));
+        return;
+    };
+    if protocol != "_tcp" && protocol != "_udp" {
+        diagnostics.push(Diagnostic::error(
+            key.clone(),
+            format!("protocol label \"{}\" must be _tcp or _udp", protocol),
+        ));
+    }
+    let Some(name) = service.strip_prefix('_') else {
+        diagnostics.push(Diagnostic::error(
+            key,
+            format!(
+                "service label \"{}\" must start with an underscore",
+                service
+            ),
+        ));
+        return;
+    };
+    if name.is_empty() || service.len() > MAX_LABEL_LEN {
+        diagnostics.push(Diagnostic::error(
+            key,
+            format!(
+                "service label \"{}\" must be 1 to {} bytes long",
+                service, MAX_LABEL_LEN
+            ),
+        ));
+        return;
+    }
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        || name.starts_with('-')
+        || name.ends_with('-')
+        || name.contains("--")
+        || !name.chars().any(|c| c.is_ascii_alphabetic())
+    {
+        diagnostics.push(Diagnostic::error(
+            key,
+            format!(
+                "service name \"{}\" may only contain letters, digits and single inner hyphens",
+                name
+            ),
+        ));
+    } else if name.len() > MAX_SERVICE_NAME_LEN {
+        diagnostics.push(Diagnostic::warning(
+            key,
+            format!(
+                "service name \"{}\" is longer than the {} characters allowed by RFC 6335",
+                name, MAX_SERVICE_NAME_LEN
+            ),
+        ));
+    }
+}
+
+fn check_instance_name(table: &str, name: &str, diagnostics: &mut Vec<Diagnostic>) {
+    let key = format!("{}.name", table);
+    if name.is_empty() {
+        diagnostics.push(Diagnostic::error(key, "instance name is empty".into()));
+    } else if name.len() > MAX_LABEL_LEN {
+        diagnostics.push(Diagnostic::error(
+            key,
+            format!(
+                "instance name is {} bytes of UTF-8, at most {} are allowed",
+                name.len(),
+                MAX_LABEL_LEN
+            ),
+        ));
+    } else if name.chars().any(char::is_control) {
+        diagnostics.push(Diagnostic::error(
+            key,
+            "instance name contains control characters".into(),
+        ));
+    }
+}
+
+fn check_txt_entry(table: &str, txt_key: &str, value: &str, diagnostics: &mut Vec<Diagnostic>) {
+    let key = format!("{}.text.{}", table, txt_key);
+    if txt_key.is_empty() {
+        diagnostics.push(Diagnostic::error(key, "TXT key is empty".into()));
+        return;
+    }
+    if !txt_key
+        .bytes()
+        .all(|b| (0x20..=0x7e).contains(&b) && b != b'=')
+    {
+        diagnostics.push(Diagnostic::error(
+            key.clone(),
+            "TXT key must be printable ASCII without '='".into(),
+        ));
+    } else if txt_key.len() > RECOMMENDED_TXT_KEY_LEN {
+        diagnostics.push(Diagnostic::warning(
+            key.clone(),
+            format!(
+                "TXT key is {} characters, {} or fewer is recommended",
+                txt_key.len(),
+                RECOMMENDED_TXT_KEY_LEN
+            ),
+        ));
+    }
+    // Each key=value pair is one length-prefixed string on the wire
+    let len = txt_key.len() + 1 + value.len();
+    if len > MAX_TXT_STRING_LEN {
+        diagnostics.push(Diagnostic::error(
+            key,
+            format!(
+                "key=value is {} bytes, at most {} fit in one TXT string",
+                len, MAX_TXT_STRING_LEN
+            ),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn severities(diagnostics: &[Diagnostic]) -> Vec<Severity> {
+        diagnostics.iter().map(|d| d.severity).collect()
+    }
+
+    fn check_type(service_type: &str) -> Vec<Severity> {
+        let mut diagnostics = Vec::new();
+        check_service_type("services.x", service_type, &mut diagnostics);
+        severities(&diagnostics)
+    }
+
+    fn check_txt(txt_key: &str, value: &str) -> Vec<Severity> {
+        let mut diagnostics = Vec::new();
+        check_txt_entry("services.x", txt_key, value, &mut diagnostics);
+        severities(&diagnostics)
+    }
+
+    #[test]
+    fn service_types() {
+        assert_eq!(check_type("_x._tcp"), []);
+        assert_eq!(check_type("_http._udp."), []);
+        assert_eq!(check_type("_device-info._tcp"), []);
+        assert_eq!(check_type("_x._sctp"), [Severity::Error]);
+        assert_eq!(check_type("_x"), [Severity::Error]);
+        assert_eq!(check_type("_a._b._tcp"), [Severity::Error]);
+        assert_eq!(check_type("x._tcp"), [Severity::Error]);
+        assert_eq!(check_type("_._tcp"), [Severity::Error]);
+    }
+
+    #[test]
+    fn service_type_hyphens() {
+        assert_eq!(check_type("_-x._tcp"), [Severity::Error]);
+        assert_eq!(check_type("_x-._tcp"), [Severity::Error]);
+        assert_eq!(check_type("_a--b._tcp"), [Severity::Error]);
+        assert_eq!(check_type("_12-34._tcp"), [Severity::Error]);
+        assert_eq!(check_type("_a_b._tcp"), [Severity::Error]);
+    }
+
+    #[test]
+    fn service_type_lengths() {
+        let fifteen = format!("_{}._tcp", "a".repeat(15));
+        let sixteen = format!("_{}._tcp", "a".repeat(16));
+        let too_long = format!("_{}._tcp", "a".repeat(MAX_LABEL_LEN));
+        assert_eq!(check_type(&fifteen), []);
+        assert_eq!(check_type(&sixteen), [Severity::Warning]);
+        assert_eq!(check_type(&too_long), [Severity::Error]);
+    }
+
+    #[test]
+    fn txt_keys() {
+        assert_eq!(check_txt("path", "/"), []);
+        assert_eq!(check_txt("flag", ""), []);
+        assert_eq!(check_txt("", "x"), [Severity::Error]);
+        assert_eq!(check_txt("a=b", "x"), [Severity::Error]);
+        assert_eq!(check_txt("tab\tkey", "x"), [Severity::Error]);
+        assert_eq!(check_txt("ké", "x"), [Severity::Error]);
+        assert_eq!(check_txt("longerkey", "x"), []);
+        assert_eq!(check_txt("longer_key", "x"), [Severity::Warning]);
+    }
+
+    #[test]
+    fn txt_lengths() {
+        // key=value is one string of at most 255 bytes
+        assert_eq!(check_txt("k", &"v".repeat(253)), []);
+        assert_eq!(check_txt("k", &"v".repeat(254)), [Severity::Error]);
+        let text: HashMap<String, String> = (0..6)
+            .map(|i| (format!("k{}", i), "v".repeat(250)))
+            .collect();
+        let mut diagnostics = Vec::new();
+        check_text("services.x", &text, &mut diagnostics);
+        assert_eq!(severities(&diagnostics), [Severity::Warning]);
+        assert_eq!(diagnostics[0].key, "services.x.text");
+    }
+
+    #[test]
+    fn duplicate_names_ignore_case() {
+        let settings: Settings = toml::from_str(
+            r#"
+            [services.a]
+            name = "Office Printer"
+            type = "_ipp._tcp"
+            port = 631
+            [services.b]
+            name = "office printer"
+            type = "_IPP._tcp"
+            port = 632
+            [services.c]
+            name = "Office Printer"
+            type = "_http._tcp"
+            port = 80
+            "#,
+        )
+        .unwrap();
+        let diagnostics = validate(&settings);
+        assert_eq!(severities(&diagnostics), [Severity::Error]);
+        assert_eq!(diagnostics[0].key, "services.b");
+    }
+
+    #[test]
+    fn interface_must_be_the_responders() {
+        let settings = |interface: &str| -> Settings {
+            toml::from_str(&format!(
+                r#"
+                backend = "builtin"
+                [mdns]
+                interface = "192.0.2.1"
+                [services.a]
+                name = "A"
+                type = "_http._tcp"
+                port = 80
+                interface = "{}"
+                "#,
+                interface
+            ))
+            .unwrap()
+        };
+        assert_eq!(severities(&validate(&settings("192.0.2.1"))), []);
+        let diagnostics = validate(&settings("198.51.100.1"));
+        assert_eq!(severities(&diagnostics), [Severity::Error]);
+        assert_eq!(diagnostics[0].key, "services.a.interface");
+    }
+}